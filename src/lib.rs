@@ -0,0 +1,1118 @@
+use itertools::Itertools;
+use std::fmt;
+use std::io;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    LoopOpen,
+    LoopClose,
+    Increment,
+    ShiftRight,
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Instruction::LoopOpen => '[',
+                Instruction::LoopClose => ']',
+                Instruction::Increment => '+',
+                Instruction::ShiftRight => '>',
+            }
+        )
+    }
+}
+
+impl Instruction {
+    pub const ALL: [Instruction; 4] = [
+        Instruction::LoopOpen,
+        Instruction::LoopClose,
+        Instruction::Increment,
+        Instruction::ShiftRight,
+    ];
+
+    pub fn from_byte(byte: u8, enc: &Encoding) -> Vec<Instruction> {
+        (0..4).map(|n| Instruction::nth(byte, n, enc)).collect()
+    }
+
+    pub fn from_pair(pair: u8, enc: &Encoding) -> Instruction {
+        match enc.opcodes.get(pair as usize) {
+            Some(&i) => i,
+            None => panic!("if you got here, you invented a new type of number"),
+        }
+    }
+
+    // decode only the `n`th instruction of a byte
+    pub fn nth(byte: u8, n: usize, enc: &Encoding) -> Instruction {
+        Instruction::from_pair((byte >> enc.shift(n)) & 0b11, enc)
+    }
+
+    pub fn from_char(c: u8) -> Option<Instruction> {
+        match c {
+            b'[' => Some(Instruction::LoopOpen),
+            b']' => Some(Instruction::LoopClose),
+            b'+' => Some(Instruction::Increment),
+            b'>' => Some(Instruction::ShiftRight),
+            _ => None,
+        }
+    }
+
+    pub fn from_chars(
+        chars: impl Iterator<Item = u8>,
+    ) -> impl Iterator<Item = Result<Instruction, CeError>> {
+        chars.map(|c| Instruction::from_char(c).ok_or(CeError::InvalidInstruction(c)))
+    }
+
+    pub fn to_pair(i: Option<&Instruction>, enc: &Encoding) -> u8 {
+        match i {
+            Some(i) => enc.opcodes.iter().position(|o| o == i).unwrap() as u8,
+            None => 0b00,
+        }
+    }
+
+    pub fn to_byte(is: Vec<Instruction>, enc: &Encoding) -> u8 {
+        (0..4).fold(0, |byte, n| {
+            byte | Instruction::to_pair(is.get(n), enc) << enc.shift(n)
+        })
+    }
+}
+
+// how instructions are packed into 2-bit pairs
+#[derive(Clone, Copy, Debug)]
+pub struct Encoding {
+    pub opcodes: [Instruction; 4], // instruction for each pair, indexed by the pair's value
+    pub bit_order: BitOrder,
+}
+
+// which end of a byte holds the first of its four instructions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitOrder {
+    Msb, // bits 7-6
+    Lsb, // bits 1-0
+}
+
+impl Default for Encoding {
+    fn default() -> Encoding {
+        Encoding {
+            opcodes: Instruction::ALL,
+            bit_order: BitOrder::Msb,
+        }
+    }
+}
+
+impl Encoding {
+    // parse an opcode map like `00=+,01=>,10=[,11=]`, which must assign every pair exactly one
+    // instruction and every instruction exactly one pair
+    pub fn parse(map: &str) -> Result<Encoding, CeError> {
+        let invalid = |reason: String| Err(CeError::InvalidOpcodeMap(reason));
+        let mut opcodes = [None; 4];
+        for entry in map.split(',') {
+            let (pair, inst) = match entry.split_once('=') {
+                Some(e) => e,
+                None => return invalid(format!("invalid entry '{}'", entry)),
+            };
+            let pair = match u8::from_str_radix(pair, 2) {
+                Ok(p) if pair.len() == 2 => p as usize,
+                _ => {
+                    return invalid(format!(
+                        "invalid opcode '{}', expected two binary digits",
+                        pair
+                    ))
+                }
+            };
+            let inst = match inst.as_bytes() {
+                [c] => Instruction::from_char(*c),
+                _ => None,
+            };
+            let inst = match inst {
+                Some(i) => i,
+                None => return invalid(format!("invalid instruction in entry '{}'", entry)),
+            };
+            if opcodes[pair].is_some() {
+                return invalid(format!("{:02b} is assigned more than once", pair));
+            }
+            if opcodes.contains(&Some(inst)) {
+                return invalid(format!("'{}' is assigned more than once", inst));
+            }
+            opcodes[pair] = Some(inst);
+        }
+
+        match opcodes {
+            [Some(a), Some(b), Some(c), Some(d)] => Ok(Encoding {
+                opcodes: [a, b, c, d],
+                ..Encoding::default()
+            }),
+            _ => invalid("all four opcodes must be assigned".to_string()),
+        }
+    }
+
+    // bits each instruction takes: enough to number every opcode
+    pub fn bits(&self) -> u32 {
+        self.opcodes.len().next_power_of_two().trailing_zeros()
+    }
+
+    // how far the `n`th instruction of a byte is shifted from the low bits
+    fn shift(&self, n: usize) -> usize {
+        match self.bit_order {
+            BitOrder::Msb => 6 - 2 * n,
+            BitOrder::Lsb => 2 * n,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopMode {
+    While,   // `[` skips the body when the cell is zero
+    DoWhile, // `[` only marks the return point, so the body always runs once
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExecError {
+    DataPointerOutOfBounds {
+        ip: usize,
+    },
+    // the limit, where it was hit, and the last `[` entered with how often its `]` has jumped
+    // back since, which is usually the loop that never ends
+    StepLimitExceeded {
+        steps: usize,
+        ip: usize,
+        loop_start: Option<usize>,
+        iterations: usize,
+    },
+    ProtectedWrite {
+        addr: usize,
+        ip: usize,
+    },
+    UninitializedCell {
+        addr: usize,
+        ip: usize,
+    },
+    MaxDpExceeded {
+        dp: usize,
+        ip: usize,
+    },
+    Quit {
+        ip: usize,
+    }, // stopped from the step debugger
+}
+
+// everything that stops `ce` short of an internal bug, each with a message and an exit code
+#[derive(Debug)]
+pub enum CeError {
+    Usage,
+    MissingValue {
+        option: String,
+        expected: &'static str,
+    },
+    InvalidValue {
+        option: String,
+        value: String,
+    },
+    UnknownOption(String),
+    UnexpectedArgument(String),
+    OptionRequires {
+        option: String,
+        requires: &'static str,
+    },
+    IsDirectory(String),
+    Io {
+        path: String,
+        source: io::Error,
+    },
+    InvalidInstruction(u8),
+    InvalidOpcodeMap(String),
+    InvalidMacro(String),
+    InvalidByte(String),
+    ProgramTooLarge(usize),
+    EmptyRing,
+    DpStartOutOfBounds(usize),
+    Exec(ExecError),
+    GoldenMismatch(String),
+    // the first step whose trace record differs, with None past the end of either trace
+    TraceMismatch {
+        step: usize,
+        expected: Option<String>,
+        actual: Option<String>,
+    },
+    NotEquivalent {
+        differing: usize,
+        inputs: usize,
+    },
+    TransformMismatch {
+        addr: usize,
+        expected: u8,
+        actual: Option<u8>, // None past the end of memory
+    },
+    SelftestFailed,
+    WarningsEmitted(usize),
+}
+
+impl CeError {
+    // 2 for a bad command line, 3 for a bad program, 4 for a file that can't be used, and 1 for
+    // a run that failed
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CeError::Usage
+            | CeError::MissingValue { .. }
+            | CeError::InvalidValue { .. }
+            | CeError::UnknownOption(_)
+            | CeError::UnexpectedArgument(_)
+            | CeError::OptionRequires { .. } => 2,
+            CeError::InvalidInstruction(_)
+            | CeError::InvalidOpcodeMap(_)
+            | CeError::InvalidMacro(_)
+            | CeError::InvalidByte(_)
+            | CeError::ProgramTooLarge(_)
+            | CeError::EmptyRing
+            | CeError::DpStartOutOfBounds(_) => 3,
+            CeError::IsDirectory(_) | CeError::Io { .. } => 4,
+            CeError::Exec(_)
+            | CeError::GoldenMismatch(_)
+            | CeError::TraceMismatch { .. }
+            | CeError::TransformMismatch { .. }
+            | CeError::NotEquivalent { .. }
+            | CeError::SelftestFailed
+            | CeError::WarningsEmitted(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for CeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CeError::Usage => write!(f, "no program file given, see `ce help` for usage"),
+            CeError::MissingValue { option, expected } => {
+                write!(f, "{} requires {}", option, expected)
+            }
+            CeError::InvalidValue { option, value } => {
+                write!(f, "invalid value '{}' for {}", value, option)
+            }
+            CeError::UnknownOption(option) => write!(f, "unknown option '{}'", option),
+            CeError::UnexpectedArgument(arg) => write!(f, "unexpected argument '{}'", arg),
+            CeError::OptionRequires { option, requires } => {
+                write!(f, "{} requires {}", option, requires)
+            }
+            CeError::IsDirectory(path) => {
+                write!(f, "'{}' is a directory, expected a program file", path)
+            }
+            CeError::Io { path, source } => write!(f, "{}: {}", path, source),
+            CeError::InvalidInstruction(c) => {
+                write!(f, "invalid instruction {:?}", char::from(*c))
+            }
+            CeError::InvalidOpcodeMap(reason) => write!(f, "opcode map: {}", reason),
+            CeError::InvalidMacro(reason) => write!(f, "macro: {}", reason),
+            CeError::InvalidByte(token) => write!(f, "invalid byte '{}'", token),
+            CeError::ProgramTooLarge(len) => {
+                write!(f, "program is {} bytes, more than the 256 available", len)
+            }
+            CeError::EmptyRing => write!(f, "--ring needs a non-empty program"),
+            CeError::DpStartOutOfBounds(addr) => {
+                write!(f, "--dp-start {} is past the end of memory", addr)
+            }
+            CeError::Exec(e) => write!(f, "{}", e),
+            CeError::GoldenMismatch(path) => write!(f, "dump does not match {}", path),
+            CeError::TraceMismatch {
+                step,
+                expected,
+                actual,
+            } => {
+                let record = |r: &Option<String>| match r {
+                    Some(r) => format!("`{}`", r),
+                    None => "the end of the trace".to_string(),
+                };
+                write!(
+                    f,
+                    "trace differs at step {}: expected {}, got {}",
+                    step,
+                    record(expected),
+                    record(actual)
+                )
+            }
+            CeError::TransformMismatch {
+                addr,
+                expected,
+                actual: Some(actual),
+            } => write!(
+                f,
+                "output differs at cell {:02x}: expected {:02x}, got {:02x}",
+                addr, expected, actual
+            ),
+            CeError::TransformMismatch { addr, expected, .. } => write!(
+                f,
+                "output differs at cell {:02x}: expected {:02x}, but memory ends there",
+                addr, expected
+            ),
+            CeError::NotEquivalent { differing, inputs } => {
+                write!(f, "programs differ on {} of {} inputs", differing, inputs)
+            }
+            CeError::SelftestFailed => write!(f, "self test failed"),
+            CeError::WarningsEmitted(n) => {
+                write!(
+                    f,
+                    "--fail-on-warning is set and {} warning(s) were printed",
+                    n
+                )
+            }
+        }
+    }
+}
+
+impl From<ExecError> for CeError {
+    fn from(e: ExecError) -> CeError {
+        CeError::Exec(e)
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecError::DataPointerOutOfBounds { ip } => {
+                write!(
+                    f,
+                    "data pointer moved past the end of memory at instruction {}",
+                    ip
+                )
+            }
+            ExecError::StepLimitExceeded {
+                steps,
+                ip,
+                loop_start,
+                iterations,
+            } => {
+                write!(f, "step limit of {} exceeded at instruction {}", steps, ip)?;
+                match loop_start {
+                    Some(start) => write!(
+                        f,
+                        ", after {} iterations of the loop at {}",
+                        iterations, start
+                    ),
+                    None => Ok(()),
+                }
+            }
+            ExecError::ProtectedWrite { addr, ip } => write!(
+                f,
+                "write to protected cell {:02x} at instruction {}",
+                addr, ip
+            ),
+            ExecError::UninitializedCell { addr, ip } => write!(
+                f,
+                "increment of uninitialized cell {:02x} at instruction {}",
+                addr, ip
+            ),
+            ExecError::MaxDpExceeded { dp, ip } => write!(
+                f,
+                "data pointer moved to {:02x}, past --max-dp, at instruction {}",
+                dp, ip
+            ),
+            ExecError::Quit { ip } => write!(f, "quit at instruction {}", ip),
+        }
+    }
+}
+
+// pack instructions four to a byte, padding the last byte with 0b00 pairs
+pub fn pack(instructions: impl Iterator<Item = Instruction>, enc: &Encoding) -> Vec<u8> {
+    instructions
+        .chunks(4)
+        .into_iter()
+        .map(|c| Instruction::to_byte(c.collect(), enc))
+        .collect()
+}
+
+// the byte format: whitespace-separated hex bytes, each decoding to its four instructions, with
+// `#` starting a comment that runs to the end of the line; `assemble --emit=hex` output reads
+// back unchanged
+pub fn decode_bytes(src: &[u8], enc: &Encoding) -> Result<Vec<Instruction>, CeError> {
+    let mut instructions = Vec::new();
+    for line in String::from_utf8_lossy(src).lines() {
+        let code = line.split('#').next().unwrap_or_default();
+        for token in code.split_whitespace() {
+            if token.len() > 2 || !token.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(CeError::InvalidByte(token.to_string()));
+            }
+            let byte = u8::from_str_radix(token, 16).unwrap();
+            instructions.extend(Instruction::from_byte(byte, enc));
+        }
+    }
+    Ok(instructions)
+}
+
+// expand repeat counts in source before parsing: `+{3}` becomes `+++` and `(+>){2}` becomes
+// `+>+>`; groups may nest, and a group without a count is kept as is
+pub fn expand_macros(src: &[u8]) -> Result<Vec<u8>, CeError> {
+    let mut pos = 0;
+    let out = expand_group(src, &mut pos)?;
+    if pos < src.len() {
+        return Err(CeError::InvalidMacro(format!(
+            "unmatched ')' at offset {}",
+            pos
+        )));
+    }
+    Ok(out)
+}
+
+// the most instructions 256 bytes can hold; no expansion needs to be longer
+const MAX_EXPANSION: usize = 256 * 4;
+
+// expand items up to the end of the source or the `)` closing the current group
+fn expand_group(src: &[u8], pos: &mut usize) -> Result<Vec<u8>, CeError> {
+    let mut out = Vec::new();
+    while let Some(&c) = src.get(*pos) {
+        let item = match c {
+            b')' => break,
+            b'(' => {
+                let open = *pos;
+                *pos += 1;
+                let body = expand_group(src, pos)?;
+                if src.get(*pos) != Some(&b')') {
+                    return Err(CeError::InvalidMacro(format!(
+                        "unmatched '(' at offset {}",
+                        open
+                    )));
+                }
+                *pos += 1;
+                body
+            }
+            b'{' => {
+                return Err(CeError::InvalidMacro(format!(
+                    "repeat count at offset {} has nothing to repeat",
+                    pos
+                )))
+            }
+            _ => {
+                *pos += 1;
+                vec![c]
+            }
+        };
+        let count = repeat_count(src, pos)?;
+        // checked before repeating, so a huge count fails instead of allocating
+        if item.len().saturating_mul(count) > MAX_EXPANSION - out.len() {
+            return Err(CeError::InvalidMacro(format!(
+                "expands past the {} instructions that fit in memory",
+                MAX_EXPANSION
+            )));
+        }
+        out.extend(item.repeat(count));
+    }
+    Ok(out)
+}
+
+// parse a `{n}` following an item, or 1 if there is none
+fn repeat_count(src: &[u8], pos: &mut usize) -> Result<usize, CeError> {
+    if src.get(*pos) != Some(&b'{') {
+        return Ok(1);
+    }
+    let start = *pos + 1;
+    let end = match src[start..].iter().position(|&b| b == b'}') {
+        Some(i) => start + i,
+        None => {
+            return Err(CeError::InvalidMacro(format!(
+                "unterminated repeat count at offset {}",
+                pos
+            )))
+        }
+    };
+    let count = String::from_utf8_lossy(&src[start..end]);
+    *pos = end + 1;
+    count
+        .parse()
+        .map_err(|_| CeError::InvalidMacro(format!("invalid repeat count '{}'", count)))
+}
+
+// index of the instruction after the `]` matching the `[` at `open`, or the end of memory if
+// it is unmatched
+pub fn loop_exit(mem: &[u8], open: usize, enc: &Encoding) -> usize {
+    loop_exit_before(mem, open, mem.len() * 4, enc)
+}
+
+// like loop_exit, but only searching instructions before `end`
+pub fn loop_exit_before(mem: &[u8], open: usize, end: usize, enc: &Encoding) -> usize {
+    let mut depth = 0;
+    for ip in open + 1..end {
+        match Instruction::nth(mem[ip / 4], ip % 4, enc) {
+            Instruction::LoopOpen => depth += 1,
+            Instruction::LoopClose if depth == 0 => return ip + 1,
+            Instruction::LoopClose => depth -= 1,
+            _ => {}
+        }
+    }
+    end
+}
+
+// a snapshot of the registers and memory between instructions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MachineState {
+    pub ip: usize,
+    pub dp: usize,
+    pub rp: usize,
+    pub mem: Vec<u8>,
+}
+
+// the interpreter as a value that can be advanced one instruction at a time, over memory
+// borrowed from the caller; advance() never allocates, so the memory can be a fixed array
+// such as `[u8; 256]`, and only the snapshots from state() and step() need a Vec
+pub struct Machine<'a> {
+    pub mem: &'a mut [u8],
+    pub encoding: Encoding,
+    pub loop_mode: LoopMode,
+    dp: usize,           // data pointer
+    ip: usize,           // instruction pointer, in instructions (four per byte)
+    rp: usize,           // return pointer
+    loop_dp: usize,      // dp at the start of the current loop iteration
+    ring: Option<usize>, // number of instructions to run in a cycle, if the program is a ring
+    // the byte being executed, latched on entry so writes to it only take effect once it is
+    // re-entered by falling through or jumping back
+    byte: Option<u8>,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(mem: &'a mut [u8], encoding: Encoding, loop_mode: LoopMode) -> Machine<'a> {
+        Machine {
+            mem,
+            encoding,
+            loop_mode,
+            dp: 0,
+            ip: 0,
+            rp: 0,
+            loop_dp: 0,
+            ring: None,
+            byte: None,
+        }
+    }
+
+    // start with the data pointer at `dp` instead of the first cell
+    pub fn with_dp(mut self, dp: usize) -> Machine<'a> {
+        self.dp = dp;
+        self.loop_dp = dp;
+        self
+    }
+
+    // treat the first `len` instructions as a ring: ip wraps back to 0 past the last of them,
+    // so the machine only stops on an error
+    pub fn with_ring(mut self, len: usize) -> Machine<'a> {
+        self.ring = Some(len);
+        self
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn dp(&self) -> usize {
+        self.dp
+    }
+
+    pub fn rp(&self) -> usize {
+        self.rp
+    }
+
+    pub fn loop_dp(&self) -> usize {
+        self.loop_dp
+    }
+
+    pub fn halted(&self) -> bool {
+        self.ip >= self.mem.len() * 4
+    }
+
+    pub fn state(&self) -> MachineState {
+        MachineState {
+            ip: self.ip,
+            dp: self.dp,
+            rp: self.rp,
+            mem: self.mem.to_vec(),
+        }
+    }
+
+    // the instruction that will execute next, or None if halted
+    pub fn current(&self) -> Option<Instruction> {
+        if self.halted() {
+            return None;
+        }
+        let byte = self.byte.unwrap_or(self.mem[self.ip / 4]);
+        Some(Instruction::nth(byte, self.ip % 4, &self.encoding))
+    }
+
+    // execute one instruction and return the state after it, or None if already halted
+    pub fn step(&mut self) -> Result<Option<MachineState>, ExecError> {
+        Ok(self.advance()?.then(|| self.state()))
+    }
+
+    // like step, but without taking a snapshot; returns false if already halted
+    pub fn advance(&mut self) -> Result<bool, ExecError> {
+        if self.halted() {
+            return Ok(false);
+        }
+        let byte = *self.byte.get_or_insert(self.mem[self.ip / 4]);
+        let dp = self.dp;
+
+        match Instruction::nth(byte, self.ip % 4, &self.encoding) {
+            Instruction::LoopOpen => {
+                if self.loop_mode == LoopMode::While && self.mem[dp] == 0 {
+                    let end = self.ring.unwrap_or(self.mem.len() * 4);
+                    self.jump(loop_exit_before(self.mem, self.ip, end, &self.encoding));
+                    return Ok(true);
+                }
+                self.rp = self.ip + 1;
+                self.loop_dp = dp;
+            }
+            Instruction::LoopClose => {
+                if self.mem[dp] != 0 {
+                    self.jump(self.rp);
+                    self.loop_dp = dp;
+                    return Ok(true);
+                }
+            }
+            Instruction::Increment => {
+                self.mem[dp] = self.mem[dp].wrapping_add(1);
+            }
+            Instruction::ShiftRight => {
+                if dp + 1 >= self.mem.len() {
+                    return Err(ExecError::DataPointerOutOfBounds { ip: self.ip });
+                }
+                self.dp += 1;
+            }
+        }
+
+        self.ip += 1;
+        if self.ip.is_multiple_of(4) {
+            self.byte = None;
+        }
+        if self.ring.is_some_and(|len| self.ip >= len) {
+            self.jump(0);
+        }
+        Ok(true)
+    }
+
+    fn jump(&mut self, ip: usize) {
+        self.ip = match self.ring {
+            Some(len) if ip >= len => 0,
+            _ => ip,
+        };
+        self.byte = None;
+    }
+
+    // a snapshot after each instruction, driving execution lazily; ends when the machine halts
+    // or an instruction fails, and step() then returns the error
+    pub fn states(&mut self) -> impl Iterator<Item = MachineState> + use<'_, 'a> {
+        std::iter::from_fn(move || self.step().ok().flatten())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // parse source and pack it into 256 bytes of memory, the way `ce run` loads a program
+    fn load(src: &str, enc: &Encoding) -> Vec<u8> {
+        let instructions: Vec<_> = Instruction::from_chars(src.bytes())
+            .map(Result::unwrap)
+            .collect();
+        let mut mem = pack(instructions.into_iter(), enc);
+        mem.resize(256, 0);
+        mem
+    }
+
+    // advance until the machine halts or fails, returning the number of instructions run
+    fn run(machine: &mut Machine) -> Result<usize, ExecError> {
+        let mut steps = 0;
+        while machine.advance()? {
+            steps += 1;
+        }
+        Ok(steps)
+    }
+
+    #[test]
+    fn ip_counts_instructions_across_bytes() {
+        let enc = Encoding::default();
+        let mut mem = load(">>>>+", &enc);
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While).with_dp(100);
+        for _ in 0..5 {
+            machine.advance().unwrap();
+        }
+        assert_eq!((machine.ip(), machine.dp()), (5, 104));
+        assert_eq!(machine.mem[104], 1);
+    }
+
+    #[test]
+    fn close_returns_into_the_middle_of_a_byte() {
+        // `[` is the third instruction of byte 0, and `]` the first of byte 1
+        let enc = Encoding::default();
+        let mut mem = load("++[+]", &enc);
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While).with_dp(100);
+        for _ in 0..5 {
+            machine.advance().unwrap();
+        }
+        assert_eq!((machine.ip(), machine.rp()), (3, 3));
+
+        // the body runs until the cell wraps from 255 to 0
+        assert!(run(&mut machine).is_ok());
+        assert_eq!(machine.mem[100], 0);
+    }
+
+    #[test]
+    fn halts_at_the_end_of_memory() {
+        let enc = Encoding::default();
+        // 2 decodes as `[[[+`, with no `]` to loop on when ip reaches the data
+        let mut mem = load("++", &enc);
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While).with_dp(100);
+        run(&mut machine).unwrap();
+        assert!(machine.halted());
+        assert_eq!(machine.ip(), 256 * 4);
+        assert_eq!(machine.current(), None);
+        assert_eq!(machine.advance(), Ok(false));
+    }
+
+    #[test]
+    fn opcode_map_assigns_every_pair() {
+        let enc = Encoding::parse("00=+,01=>,10=[,11=]").unwrap();
+        assert_eq!(
+            enc.opcodes,
+            [
+                Instruction::Increment,
+                Instruction::ShiftRight,
+                Instruction::LoopOpen,
+                Instruction::LoopClose
+            ]
+        );
+        assert_eq!(
+            Instruction::nth(0b00_01_10_11, 0, &enc),
+            Instruction::Increment
+        );
+        assert_eq!(
+            Instruction::nth(0b00_01_10_11, 3, &enc),
+            Instruction::LoopClose
+        );
+    }
+
+    #[test]
+    fn opcode_map_must_be_a_bijection() {
+        let rejected = |map| matches!(Encoding::parse(map), Err(CeError::InvalidOpcodeMap(_)));
+        assert!(rejected("00=+,01=+,10=[,11=]")); // an instruction twice
+        assert!(rejected("00=+,00=>,10=[,11=]")); // a pair twice
+        assert!(rejected("00=+,01=>,10=[")); // a pair left out
+        assert!(rejected("00=+,01=>,10=[,2=]"));
+        assert!(rejected("00=+,01=>,10=[,11=x"));
+        assert!(rejected("00+,01=>,10=[,11=]"));
+    }
+
+    #[test]
+    fn loop_exit_finds_the_matching_close() {
+        let enc = Encoding::default();
+        let mem = load("[+]+", &enc);
+        assert_eq!(loop_exit(&mem, 0, &enc), 3);
+        let mem = load("[[+]>]+", &enc);
+        assert_eq!(loop_exit(&mem, 0, &enc), 6);
+        assert_eq!(loop_exit(&mem, 1, &enc), 4);
+        // the search can be bounded before the end of memory
+        assert_eq!(loop_exit_before(&mem, 0, 5, &enc), 5);
+    }
+
+    #[test]
+    fn unmatched_open_exits_at_the_end_of_memory() {
+        let enc = Encoding::default();
+        let mem = load("+[+", &enc);
+        assert_eq!(loop_exit(&mem, 1, &enc), 256 * 4);
+    }
+
+    #[test]
+    fn open_skips_the_body_on_a_zero_cell() {
+        let enc = Encoding::default();
+        let mut mem = load("[>]", &enc);
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While).with_dp(100);
+        machine.advance().unwrap();
+        assert_eq!((machine.ip(), machine.dp()), (3, 100));
+    }
+
+    #[test]
+    fn do_while_runs_the_body_once() {
+        let enc = Encoding::default();
+        let mut mem = load("[>]", &enc);
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::DoWhile).with_dp(100);
+        for _ in 0..3 {
+            machine.advance().unwrap();
+        }
+        assert_eq!((machine.ip(), machine.dp()), (3, 101));
+    }
+
+    #[test]
+    fn loop_dp_follows_each_iteration() {
+        let enc = Encoding::default();
+        let mut mem = load("+[>+]", &enc);
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While).with_dp(100);
+        machine.advance().unwrap();
+        machine.advance().unwrap();
+        assert_eq!(machine.loop_dp(), 100);
+        for _ in 0..3 {
+            machine.advance().unwrap();
+        }
+        // `]` jumped back with dp on 101, which is where the next iteration starts
+        assert_eq!((machine.ip(), machine.loop_dp()), (2, 101));
+    }
+
+    #[test]
+    fn states_are_produced_lazily() {
+        let enc = Encoding::default();
+        let mut mem = load(">>>>+", &enc);
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While).with_dp(100);
+        let states: Vec<_> = machine.states().take(3).collect();
+        assert_eq!(
+            states.iter().map(|s| s.dp).collect::<Vec<_>>(),
+            [101, 102, 103]
+        );
+        // nothing beyond the snapshots taken has run
+        assert_eq!(machine.ip(), 3);
+    }
+
+    #[test]
+    fn states_end_when_the_machine_halts() {
+        let enc = Encoding::default();
+        let mut mem = load("++", &enc);
+        let mut copy = mem.clone();
+        let steps = run(&mut Machine::new(&mut copy, enc, LoopMode::While).with_dp(100)).unwrap();
+
+        // one snapshot per instruction, ending with the same memory advance() leaves
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While).with_dp(100);
+        let states: Vec<_> = machine.states().collect();
+        assert_eq!(states.len(), steps);
+        assert_eq!(states.last().unwrap().ip, 256 * 4);
+        assert_eq!(states.last().unwrap().mem, copy);
+        assert_eq!(machine.states().count(), 0);
+    }
+
+    #[test]
+    fn step_returns_the_state_after_each_instruction() {
+        let enc = Encoding::default();
+        let mut mem = load("+>", &enc);
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While).with_dp(200);
+        let state = machine.step().unwrap().unwrap();
+        assert_eq!(
+            (state.ip, state.dp, state.rp, state.mem[200]),
+            (1, 200, 0, 1)
+        );
+        assert_eq!(state, machine.state());
+    }
+
+    #[test]
+    fn step_fails_without_moving_past_the_error() {
+        let enc = Encoding::default();
+        let mut mem = load(">", &enc);
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While).with_dp(255);
+        assert_eq!(
+            machine.step(),
+            Err(ExecError::DataPointerOutOfBounds { ip: 0 })
+        );
+        assert_eq!((machine.ip(), machine.dp()), (0, 255));
+    }
+
+    #[test]
+    fn step_returns_none_once_halted() {
+        let enc = Encoding::default();
+        let mut mem = vec![0xaa]; // `++++` over a single cell
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While);
+        assert_eq!(run(&mut machine), Ok(4));
+        assert_eq!(machine.step(), Ok(None));
+    }
+
+    #[test]
+    fn with_dp_sets_where_the_program_writes() {
+        let enc = Encoding::default();
+        let mut mem = load("+", &enc);
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While).with_dp(42);
+        assert_eq!((machine.dp(), machine.loop_dp()), (42, 42));
+        machine.advance().unwrap();
+        assert_eq!(mem[42], 1);
+    }
+
+    // the message of the InvalidMacro error expanding `src` fails with
+    fn macro_error(src: &str) -> String {
+        match expand_macros(src.as_bytes()) {
+            Err(CeError::InvalidMacro(reason)) => reason,
+            other => panic!("expected InvalidMacro, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn macros_repeat_instructions_and_groups() {
+        assert_eq!(expand_macros(b"+{5}").unwrap(), b"+++++");
+        assert_eq!(expand_macros(b"(+>){3}").unwrap(), b"+>+>+>");
+        assert_eq!(expand_macros(b"((+){2}>){2}[").unwrap(), b"++>++>[");
+        assert_eq!(expand_macros(b"(+>)+{0}").unwrap(), b"+>");
+    }
+
+    #[test]
+    fn macros_reject_malformed_groups_and_counts() {
+        assert_eq!(macro_error("(+>"), "unmatched '(' at offset 0");
+        assert_eq!(macro_error("+>)"), "unmatched ')' at offset 2");
+        assert_eq!(
+            macro_error("{2}"),
+            "repeat count at offset 0 has nothing to repeat"
+        );
+        assert_eq!(macro_error("+{2"), "unterminated repeat count at offset 1");
+        assert_eq!(macro_error("+{x}"), "invalid repeat count 'x'");
+    }
+
+    #[test]
+    fn macros_stop_at_what_fits_in_memory() {
+        assert_eq!(expand_macros(b"+{1024}").unwrap().len(), 1024);
+        let too_long = "expands past the 1024 instructions that fit in memory";
+        assert_eq!(macro_error("+{1025}"), too_long);
+        assert_eq!(macro_error("+{1000}>{25}"), too_long);
+        assert_eq!(macro_error("+{18446744073709551615}"), too_long);
+        assert_eq!(macro_error("((+){100000}){100000}"), too_long);
+    }
+
+    #[test]
+    fn bit_order_picks_the_end_of_the_byte_that_runs_first() {
+        let msb = Encoding::default();
+        let lsb = Encoding {
+            bit_order: BitOrder::Lsb,
+            ..Encoding::default()
+        };
+        assert_eq!(
+            Instruction::to_byte(vec![Instruction::Increment], &msb),
+            0x80
+        );
+        assert_eq!(
+            Instruction::to_byte(vec![Instruction::Increment], &lsb),
+            0x02
+        );
+
+        let program = vec![
+            Instruction::Increment,
+            Instruction::ShiftRight,
+            Instruction::LoopClose,
+            Instruction::LoopOpen,
+        ];
+        for enc in [msb, lsb] {
+            let byte = Instruction::to_byte(program.clone(), &enc);
+            assert_eq!(Instruction::from_byte(byte, &enc), program);
+            for (n, &inst) in program.iter().enumerate() {
+                assert_eq!(Instruction::nth(byte, n, &enc), inst);
+            }
+        }
+    }
+
+    #[test]
+    fn lsb_programs_run_the_low_bits_first() {
+        let lsb = Encoding {
+            bit_order: BitOrder::Lsb,
+            ..Encoding::default()
+        };
+        let mut mem = load("++>", &lsb);
+        let mut machine = Machine::new(&mut mem, lsb, LoopMode::While).with_dp(100);
+        for _ in 0..3 {
+            machine.advance().unwrap();
+        }
+        assert_eq!((machine.dp(), machine.mem[100]), (101, 2));
+    }
+
+    #[test]
+    fn ring_runs_its_instructions_in_a_cycle() {
+        let enc = Encoding::default();
+        let mut mem = load("+>+", &enc);
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While)
+            .with_dp(100)
+            .with_ring(3);
+        let mut ips = Vec::new();
+        for _ in 0..7 {
+            ips.push(machine.ip());
+            machine.advance().unwrap();
+        }
+        assert_eq!(ips, [0, 1, 2, 0, 1, 2, 0]);
+        assert_eq!(machine.mem[100..103], [1, 2, 2]);
+    }
+
+    #[test]
+    fn ring_jumps_past_its_end_wrap_to_the_start() {
+        // with cell 100 zero, `[` skips to the end of memory, which is past the ring
+        let enc = Encoding::default();
+        let mut mem = load(">[+", &enc);
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While)
+            .with_dp(99)
+            .with_ring(3);
+        machine.advance().unwrap();
+        machine.advance().unwrap();
+        assert_eq!((machine.ip(), machine.dp()), (0, 100));
+    }
+
+    #[test]
+    fn custom_maps_decode_every_pair() {
+        let enc = Encoding::parse("00=+,01=>,10=[,11=]").unwrap();
+        assert_eq!(Instruction::from_pair(0b01, &enc), Instruction::ShiftRight);
+        assert_eq!(
+            Instruction::from_byte(0b00_01_10_11, &enc),
+            [
+                Instruction::Increment,
+                Instruction::ShiftRight,
+                Instruction::LoopOpen,
+                Instruction::LoopClose
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_over_a_fixed_array() {
+        let enc = Encoding::default();
+        let mut mem = [0u8; 256];
+        mem[0] = Instruction::to_byte(
+            vec![
+                Instruction::Increment,
+                Instruction::ShiftRight,
+                Instruction::LoopOpen,
+            ],
+            &enc,
+        );
+        let mut machine = Machine::new(&mut mem, enc, LoopMode::While).with_dp(100);
+        assert_eq!(run(&mut machine), Ok(3));
+        assert_eq!(mem[100..102], [1, 0]);
+    }
+
+    #[test]
+    fn byte_format_decodes_hex_with_comments() {
+        let enc = Encoding::default();
+        let decoded = decode_bytes(b"b0 # +>[[\n\n  0A\t2\n# 00\n", &enc).unwrap();
+        let expected: Vec<_> = [0xb0, 0x0a, 0x02]
+            .iter()
+            .flat_map(|&b| Instruction::from_byte(b, &enc))
+            .collect();
+        assert_eq!(decoded, expected);
+        assert_eq!(pack(decoded.into_iter(), &enc), [0xb0, 0x0a, 0x02]);
+    }
+
+    #[test]
+    fn byte_format_rejects_anything_but_bytes() {
+        let enc = Encoding::default();
+        for token in ["zz", "100", "0x1", "+>"] {
+            assert!(
+                matches!(decode_bytes(token.as_bytes(), &enc), Err(CeError::InvalidByte(t)) if t == token),
+                "{} was accepted",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn step_limit_names_the_loop_it_was_stuck_in() {
+        let stuck = ExecError::StepLimitExceeded {
+            steps: 1000,
+            ip: 3,
+            loop_start: Some(2),
+            iterations: 997,
+        };
+        assert_eq!(
+            stuck.to_string(),
+            "step limit of 1000 exceeded at instruction 3, after 997 iterations of the loop at 2"
+        );
+        let straight = ExecError::StepLimitExceeded {
+            steps: 2,
+            ip: 2,
+            loop_start: None,
+            iterations: 0,
+        };
+        assert_eq!(
+            straight.to_string(),
+            "step limit of 2 exceeded at instruction 2"
+        );
+    }
+}
@@ -1,159 +1,2847 @@
+use carter_emu::{
+    decode_bytes, expand_macros, loop_exit, pack, BitOrder, CeError, Encoding, ExecError,
+    Instruction, LoopMode, Machine,
+};
 use itertools::Itertools;
 use pretty_hex::*;
+use std::collections::HashSet;
 use std::env;
 use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-fn main() -> Result<(), std::io::Error> {
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+// subcommands and what they do, for `ce help`
+#[rustfmt::skip]
+const COMMANDS: [(&str, &str); 8] = [
+    ("run",                   "execute a program and dump memory (the default)"),
+    ("transform",             "run on --in data and check the result against --expect"),
+    ("assemble",              "pack a program and print its bytes"),
+    ("annotate",              "list each instruction with its index and byte:slot"),
+    ("equiv",                 "check that two programs leave the same data"),
+    ("info",                  "report on a program; --encoding for its packing"),
+    ("selftest",              "check the encoder and interpreter on this build"),
+    ("help",                  "show this message"),
+];
+
+// options and their arguments, for `ce help`; which commands accept each is below
+#[rustfmt::skip]
+const OPTIONS: [(&str, &str); 46] = [
+    ("--step",                "pause before every instruction"),
+    ("--break <index>",       "pause before the instruction at <index>"),
+    ("--run-until <watch>",   "pause once a write makes cell=A,value=N true"),
+    ("--max-steps <n>",       "fail after <n> instructions"),
+    ("--trace",               "print every instruction as it executes"),
+    ("--trace-cells [S:E]",   "append cells S..E to each trace record"),
+    ("--trace-relative",      "show dp relative to the loop start in traces"),
+    ("--debug-on-interrupt",  "pause on ctrl-c instead of exiting"),
+    ("--step-delay <ms>",     "pace execution to one instruction per <ms>"),
+    ("--tui",                 "show a live view of memory and registers"),
+    ("--protect S:E",         "fail on writes to cells S..E"),
+    ("--track-uninit",        "fail on incrementing a cell nothing was loaded into"),
+    ("--warn-self-modify",    "warn when the program writes into itself"),
+    ("--fail-on-warning",     "exit non-zero if any warning was printed"),
+    ("--tape-dump-on-error",  "dump memory when execution fails"),
+    ("--dp-start <addr>",     "start the data pointer at <addr>"),
+    ("--max-dp <addr>",       "fail if dp moves past <addr>"),
+    ("--tape-fill <byte>",    "initial value of the cells after the program"),
+    ("--tape-file <file>",    "load the cells after the program from <file>"),
+    ("--in <file>",           "same as --tape-file"),
+    ("--over <file>",         "same as --tape-file, for equiv"),
+    ("--random <n>",          "equiv also compares over <n> random inputs"),
+    ("--expect <file>",       "check the cells after the program against <file>"),
+    ("--no-resize",           "keep memory at the program's own length"),
+    ("--ring",                "wrap ip back to 0 after the last instruction"),
+    ("--repeat <n>",          "run <n> times against the same memory"),
+    ("--halt-on-stable",      "stop once a pass leaves memory unchanged"),
+    ("--loop=while|do-while", "whether `[` skips the body on a zero cell"),
+    ("--opcode-map <map>",    "pair-to-instruction map, e.g. 00=+,01=>,10=[,11=]"),
+    ("--bit-order=msb|lsb",   "which end of a byte holds its first instruction"),
+    ("--macros",              "expand `+{n}` and `(...){n}` in source"),
+    ("--format=source|bytes", "read the program as `[]+>` or as hex bytes"),
+    ("--golden <file>",       "compare the final dump against <file>"),
+    ("--trace-golden <file>", "check the trace against one recorded in <file>"),
+    ("--record-trace <file>", "record the trace to <file>, for --trace-golden"),
+    ("--out-tape <file>",     "write the final memory to <file>"),
+    ("--out-tape-trim",       "leave trailing untouched cells out of --out-tape"),
+    ("--export-image <file>", "write the final memory as a PGM image"),
+    ("--dump-interval <n>",   "write memory to <file>.dump.<k> every <n> steps"),
+    ("--stats-json <file|->", "write a run summary as JSON"),
+    ("--time-opcodes",        "report the average time per instruction"),
+    ("--dump-brackets",       "print where each `[` skips to before running"),
+    ("--ip-trace <file>",     "write the visited instruction indices to <file>"),
+    ("--emit=hex|c|rust",     "output format for assemble"),
+    ("--encoding",            "info reports how the program packs"),
+    ("--help",                "show this message"),
+];
+
+// the options each command accepts, named up to any `=`; anything else is an unknown option
+#[rustfmt::skip]
+const RUN_OPTIONS: &[&str] = &[
+    "--step", "--break", "--run-until", "--max-steps", "--trace", "--trace-cells",
+    "--trace-relative", "--debug-on-interrupt", "--step-delay", "--tui", "--protect",
+    "--track-uninit", "--warn-self-modify", "--fail-on-warning", "--tape-dump-on-error",
+    "--dp-start", "--max-dp", "--tape-fill", "--tape-file", "--in", "--expect", "--no-resize",
+    "--ring", "--repeat", "--halt-on-stable", "--loop", "--opcode-map", "--bit-order",
+    "--macros", "--format", "--golden", "--trace-golden", "--record-trace", "--out-tape",
+    "--out-tape-trim", "--export-image", "--dump-interval", "--stats-json", "--time-opcodes",
+    "--dump-brackets", "--ip-trace",
+];
+#[rustfmt::skip]
+const EQUIV_OPTIONS: &[&str] = &[
+    "--max-steps", "--protect", "--track-uninit", "--dp-start", "--max-dp", "--tape-fill",
+    "--tape-file", "--in", "--over", "--random", "--loop", "--opcode-map", "--bit-order",
+    "--macros", "--format",
+];
+const ASSEMBLE_OPTIONS: &[&str] = &[
+    "--opcode-map",
+    "--bit-order",
+    "--macros",
+    "--format",
+    "--emit",
+];
+const ANNOTATE_OPTIONS: &[&str] = &["--opcode-map", "--bit-order", "--macros", "--format"];
+const INFO_OPTIONS: &[&str] = &[
+    "--opcode-map",
+    "--bit-order",
+    "--macros",
+    "--format",
+    "--encoding",
+];
+
+fn help() -> String {
+    let mut text = format!(
+        "carter-emu v{}\n\nusage: ce [command] [options] <file>\n\ncommands:\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    for (name, about) in COMMANDS {
+        text += &format!("  {:<24}{}\n", name, about);
+    }
+    text += "\noptions:\n";
+    for (option, about) in OPTIONS {
+        text += &format!("  {:<24}{}\n", option, about);
+    }
+    text
+}
+
+fn run(args: &[String]) -> Result<(), CeError> {
+    let (command, args) = match args.first().map(String::as_str) {
+        Some(name) if COMMANDS.iter().any(|&(c, _)| c == name) => (name, &args[1..]),
+        _ => ("run", args),
+    };
+    if args.iter().any(|a| a == "--help" || a == "-h") {
+        print!("{}", help());
+        return Ok(());
+    }
+
+    match command {
+        "help" => {
+            print!("{}", help());
+            Ok(())
+        }
+        "selftest" => {
+            println!("carter-emu v{}", env!("CARGO_PKG_VERSION"));
+            match selftest() {
+                true => Ok(()),
+                false => Err(CeError::SelftestFailed),
+            }
+        }
+        "assemble" => assemble(args),
+        "annotate" => annotate(args),
+        "equiv" => equiv(args),
+        "info" => info(args),
+        "transform" => run_program(args, true),
+        _ => run_program(args, false),
+    }
+}
+
+// steps each program may take per input in `ce equiv` when --max-steps isn't given
+const EQUIV_MAX_STEPS: usize = 1_000_000;
+
+// run two programs over the same inputs and compare the cells after the longer of them, which
+// is where the input goes; both must also finish the same way
+fn equiv(args: &[String]) -> Result<(), CeError> {
+    let mut opts = Options::parse(args, 2, EQUIV_OPTIONS)?;
+    opts.max_steps.get_or_insert(EQUIV_MAX_STEPS);
+
+    let mut programs = Vec::new();
+    for path in [&opts.path, &opts.other_paths[0]] {
+        programs.push(pack_program(&load_program(path, &opts)?, &opts.encoding)?);
+    }
+    let data_start = programs.iter().map(Vec::len).max().unwrap_or(0);
+    check_dp_start(&opts, 256)?;
+
+    let mut inputs = vec![match &opts.tape_file {
+        Some(path) => fs::read(path).map_err(io_error(path))?,
+        None => Vec::new(),
+    }];
+    // xorshift, seeded the same every time so a reported difference can be reproduced
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    for _ in 0..opts.random_tapes {
+        let input = (data_start..256)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect();
+        inputs.push(input);
+    }
+
+    let mut differing = 0;
+    for (n, input) in inputs.iter().enumerate() {
+        let runs: Vec<_> = programs
+            .iter()
+            .map(|program| {
+                let mut mem = program.clone();
+                mem.resize(256, opts.tape_fill);
+                let fits = input.len().min(256 - data_start);
+                mem[data_start..data_start + fits].copy_from_slice(&input[..fits]);
+                let result = execute_debug(
+                    &mut mem,
+                    program.len(),
+                    data_start + fits,
+                    None,
+                    &opts,
+                    &mut Stats::default(),
+                    &mut Console {
+                        input: &mut io::stdin().lock(),
+                        output: &mut io::stdout(),
+                    },
+                );
+                (status(result.as_ref().err()), mem.split_off(data_start))
+            })
+            .collect();
+        let ((status_a, data_a), (status_b, data_b)) = (&runs[0], &runs[1]);
+
+        let differs = (0..data_a.len()).find(|&i| data_a[i] != data_b[i]);
+        if status_a != status_b {
+            println!("input {}: {} vs {}", n, status_a, status_b);
+        } else if let Some(i) = differs {
+            println!(
+                "input {}: cell {:02x} is {:02x} vs {:02x}",
+                n,
+                data_start + i,
+                data_a[i],
+                data_b[i]
+            );
+        } else {
+            continue;
+        }
+        differing += 1;
+    }
+
+    match differing {
+        0 => {
+            println!("equivalent over {} inputs", inputs.len());
+            Ok(())
+        }
+        _ => Err(CeError::NotEquivalent {
+            differing,
+            inputs: inputs.len(),
+        }),
+    }
+}
+
+fn assemble(args: &[String]) -> Result<(), CeError> {
+    let opts = Options::parse(args, 1, ASSEMBLE_OPTIONS)?;
+    let bytes = pack_program(&load_program(&opts.path, &opts)?, &opts.encoding)?;
+    print!("{}", opts.emit.format(&bytes));
+    Ok(())
+}
+
+fn info(args: &[String]) -> Result<(), CeError> {
+    let opts = Options::parse(args, 1, INFO_OPTIONS)?;
+    if !opts.encoding_info {
+        return Err(CeError::OptionRequires {
+            option: "info".to_string(),
+            requires: "--encoding",
+        });
+    }
+    print!(
+        "{}",
+        encoding_report(&load_program(&opts.path, &opts)?, &opts.encoding)?
+    );
+    Ok(())
+}
+
+// how densely a program packs under the chosen encoding; everything past the last instruction
+// of the last byte is padding, which still decodes as whatever the map gives pair 0b00
+fn encoding_report(instructions: &[Instruction], enc: &Encoding) -> Result<String, CeError> {
+    let bytes = pack_program(instructions, enc)?;
+    let bits = enc.bits() as usize;
+    let map = enc
+        .opcodes
+        .iter()
+        .enumerate()
+        .map(|(pair, inst)| format!("{:02b}={}", pair, inst))
+        .join(",");
+    let mut report = format!("{:<16} {}\n", "instructions", instructions.len());
+    report += &format!("{:<16} {}\n", "bits each", bits);
+    report += &format!("{:<16} {} of 256\n", "bytes", bytes.len());
+    report += &format!(
+        "{:<16} {}, decoding as {}\n",
+        "padding pairs",
+        bytes.len() * 8 / bits - instructions.len(),
+        enc.opcodes[0]
+    );
+    if !bytes.is_empty() {
+        let density = (instructions.len() * bits) as f64 / (bytes.len() * 8) as f64;
+        report += &format!("{:<16} {:.1}%\n", "density", density * 100.0);
+    }
+    report += &format!("{:<16} {}\n", "opcode map", map);
+    let order = match enc.bit_order {
+        BitOrder::Msb => "msb",
+        BitOrder::Lsb => "lsb",
+    };
+    Ok(report + &format!("{:<16} {}\n", "bit order", order))
+}
+
+fn annotate(args: &[String]) -> Result<(), CeError> {
+    let opts = Options::parse(args, 1, ANNOTATE_OPTIONS)?;
+    print!("{}", annotation(&load_program(&opts.path, &opts)?));
+    Ok(())
+}
+
+// each instruction with its index and the byte:slot it packs into, for working out --break
+// offsets
+fn annotation(instructions: &[Instruction]) -> String {
+    instructions
+        .iter()
+        .enumerate()
+        .map(|(ip, inst)| format!("[{}] {:02x}:{} {}\n", ip, ip / 4, ip % 4, inst))
+        .collect()
+}
+
+// `transform` runs the same way, but insists on --in and --expect
+fn run_program(args: &[String], transform: bool) -> Result<(), CeError> {
     println!("carter-emu v{}", env!("CARGO_PKG_VERSION"));
 
     // load program from file into memory
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        panic!("usage: ce <filename>");
+    let mut opts = Options::parse(args, 1, RUN_OPTIONS)?;
+    if transform && (opts.tape_file.is_none() || opts.expect.is_none()) {
+        return Err(CeError::OptionRequires {
+            option: "transform".to_string(),
+            requires: "--in and --expect",
+        });
     }
+    opts.interactive = io::stdin().is_terminal();
+    if opts.tui && !io::stdout().is_terminal() {
+        warn("--tui needs a terminal, running without it".to_string());
+        opts.tui = false;
+    }
+    let instructions = load_program(&opts.path, &opts)?;
+    if opts.dump_brackets {
+        eprint!("{}", bracket_table(&instructions));
+    }
+    let ring = opts.ring.then_some(instructions.len());
+    if ring == Some(0) {
+        return Err(CeError::EmptyRing);
+    }
+
+    // prep the memory by loading the instructions starting at 0x00, then extending to 256 bytes
+    // unless --no-resize asks for exactly the program's bytes
+    let mut mem = pack_program(&instructions, &opts.encoding)?;
+    let program_len = mem.len();
+    if !opts.no_resize {
+        mem.resize(256, opts.tape_fill);
+    }
+
+    // --tape-file fills the data cells after the program, dropping whatever doesn't fit
+    let mut loaded_len = program_len;
+    if let Some(path) = &opts.tape_file {
+        let data = fs::read(path).map_err(io_error(path))?;
+        let fits = data.len().min(mem.len() - program_len);
+        if fits < data.len() {
+            warn(format!(
+                "{} is {} bytes but only {} fit after the program, truncating",
+                path,
+                data.len(),
+                fits
+            ));
+        }
+        mem[program_len..program_len + fits].copy_from_slice(&data[..fits]);
+        loaded_len += fits;
+    }
+    check_dp_start(&opts, mem.len())?;
+
+    if opts.debug_on_interrupt {
+        install_interrupt_handler();
+    }
+
+    let mut stats = Stats::default();
+    let start = Instant::now();
+    let result = execute_runs(
+        &mut mem,
+        program_len,
+        loaded_len,
+        ring,
+        &opts,
+        &mut stats,
+        &mut Console {
+            input: &mut io::stdin().lock(),
+            output: &mut io::stdout(),
+        },
+    );
+    let elapsed = start.elapsed();
 
-    let mut in_bytes: Vec<u8> = fs::read(&args[1])?;
+    if let Some(path) = &opts.stats_json {
+        let json = stats.to_json(elapsed, result.as_ref().err());
+        if path == "-" {
+            eprintln!("{}", json);
+        } else {
+            fs::write(path, json + "\n").map_err(io_error(path))?;
+        }
+    }
+
+    if opts.time_opcodes {
+        eprint!("{}", stats.opcode_report());
+    }
+
+    if let Some(path) = &opts.ip_trace {
+        fs::write(path, format!("{}\n", stats.path.iter().format(" "))).map_err(io_error(path))?;
+    }
+
+    if let Some(path) = &opts.record_trace {
+        let trace: String = stats.trace.iter().map(|r| r.clone() + "\n").collect();
+        fs::write(path, trace).map_err(io_error(path))?;
+        eprintln!("recorded {} steps to {}", stats.trace.len(), path);
+    }
+
+    // a missing golden is an error rather than a fresh recording, so a mistyped path can't pass
+    if let Some(path) = &opts.trace_golden {
+        let golden = fs::read_to_string(path).map_err(io_error(path))?;
+        let expected: Vec<String> = golden
+            .lines()
+            .map(|l| l.trim_end().to_string())
+            .filter(|l| !l.is_empty())
+            .collect();
+        let len = expected.len().max(stats.trace.len());
+        if let Some(i) = (0..len).find(|&i| expected.get(i) != stats.trace.get(i)) {
+            return Err(CeError::TraceMismatch {
+                step: i + 1,
+                expected: expected.get(i).cloned(),
+                actual: stats.trace.get(i).cloned(),
+            });
+        }
+    }
+
+    if let Err(e) = result {
+        if opts.tape_dump_on_error {
+            println!("{}", pretty_hex(&mem));
+        }
+        return Err(e.into());
+    }
+
+    if let Some(path) = &opts.out_tape {
+        let mut tape = &mem[..];
+        if opts.out_tape_trim {
+            // drop trailing cells still holding their initial fill
+            let used = tape
+                .iter()
+                .rposition(|&b| b != opts.tape_fill)
+                .map_or(0, |i| i + 1);
+            tape = &tape[..used];
+        }
+        fs::write(path, tape).map_err(io_error(path))?;
+    }
+
+    if let Some(path) = &opts.export_image {
+        fs::write(path, pgm(&mem)).map_err(io_error(path))?;
+    }
+
+    if let Some(path) = &opts.expect {
+        // the output is read from the same cells the input was loaded into
+        let expected = fs::read(path).map_err(io_error(path))?;
+        let output = &mem[program_len..];
+        let differs = (0..expected.len()).find(|&i| output.get(i) != Some(&expected[i]));
+        if let Some(i) = differs {
+            return Err(CeError::TransformMismatch {
+                addr: program_len + i,
+                expected: expected[i],
+                actual: output.get(i).copied(),
+            });
+        }
+        println!("output matches {}", path);
+    } else if let Some(golden) = &opts.golden {
+        let expected = fs::read_to_string(golden).map_err(io_error(golden))?;
+        if !compare_golden(&pretty_hex(&mem), &expected) {
+            return Err(CeError::GoldenMismatch(golden.clone()));
+        }
+    } else {
+        println!("{}", pretty_hex(&mem));
+    }
+
+    // --fail-on-warning turns a clean run that printed warnings into a failure
+    let warnings = WARNINGS.load(Ordering::SeqCst);
+    if opts.fail_on_warning && warnings > 0 {
+        return Err(CeError::WarningsEmitted(warnings));
+    }
+    Ok(())
+}
+
+// --dp-start must name a cell of the memory the program runs in; an empty memory (an empty
+// program under --no-resize) has no cells, but nothing runs in it to use dp either
+fn check_dp_start(opts: &Options, mem_len: usize) -> Result<(), CeError> {
+    if mem_len > 0 && opts.dp_start >= mem_len {
+        return Err(CeError::DpStartOutOfBounds(opts.dp_start));
+    }
+    Ok(())
+}
+
+// read and parse a source file
+fn load_program(path: &str, opts: &Options) -> Result<Vec<Instruction>, CeError> {
+    if fs::metadata(path).is_ok_and(|m| m.is_dir()) {
+        return Err(CeError::IsDirectory(path.to_string()));
+    }
+    let mut in_bytes: Vec<u8> = fs::read(path).map_err(io_error(path))?;
+    if in_bytes.starts_with(b"#!") {
+        // skip a shebang line so programs can be run directly
+        let end = in_bytes
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(in_bytes.len(), |i| i + 1);
+        in_bytes.drain(..end);
+    }
+    if opts.format == Format::Bytes {
+        return decode_bytes(&in_bytes, &opts.encoding);
+    }
     in_bytes.pop(); // remove trailing newline
-    let instructions = Instruction::from_chars(in_bytes.into_iter());
+    if opts.macros {
+        in_bytes = expand_macros(&in_bytes)?;
+    }
+    Instruction::from_chars(in_bytes.into_iter()).collect()
+}
 
-    // prep the memory by loading the instructions starting at 0x00, then extending to 256 bytes
-    let mut mem: Vec<u8> = instructions
-        .into_iter()
-        .chunks(4)
-        .into_iter()
-        .map(|c| Instruction::to_byte(c.collect()))
-        .collect();
-    if mem.len() > 256 {
-        panic!("Error: program length exceeds 256 bytes");
+// pack a program, which has to fit in the 256 bytes of memory
+fn pack_program(instructions: &[Instruction], enc: &Encoding) -> Result<Vec<u8>, CeError> {
+    let bytes = pack(instructions.iter().copied(), enc);
+    if bytes.len() > 256 {
+        return Err(CeError::ProgramTooLarge(bytes.len()));
+    }
+    Ok(bytes)
+}
+
+// where each `[` in the source jumps when it skips its body, by instruction index: past the
+// `]` that closes it at the same nesting depth, as loop_exit finds it; a `]` doesn't use this
+// nesting, as it always returns to the last `[` entered
+fn bracket_table(instructions: &[Instruction]) -> String {
+    let mut open = Vec::new();
+    let mut skips = Vec::new();
+    for (ip, inst) in instructions.iter().enumerate() {
+        match inst {
+            Instruction::LoopOpen => open.push(ip),
+            Instruction::LoopClose => {
+                if let Some(start) = open.pop() {
+                    skips.push((start, Some(ip + 1)));
+                }
+            }
+            _ => {}
+        }
     }
+    skips.extend(open.into_iter().map(|start| (start, None)));
+    skips.sort();
+
+    let mut table = String::new();
+    for (start, end) in skips {
+        table += &match end {
+            Some(end) => format!("[ at {} skips to {}\n", start, end),
+            None => format!("[ at {} skips past the end of the program\n", start),
+        };
+    }
+    if instructions.contains(&Instruction::LoopClose) {
+        table += "] returns to the last [ entered\n";
+    }
+    table
+}
+
+// wrap a failed read or write of `path`
+fn io_error(path: &str) -> impl FnOnce(io::Error) -> CeError + '_ {
+    move |source| CeError::Io {
+        path: path.to_string(),
+        source,
+    }
+}
+
+// check the encoder and interpreter invariants on this build, for `ce selftest`
+fn selftest() -> bool {
+    let enc = Encoding::default();
+    let mut passed = true;
+    let mut check = |name: &str, ok: bool| {
+        println!("{:<32} {}", name, if ok { "pass" } else { "FAIL" });
+        passed &= ok;
+    };
+
+    check(
+        "byte round-trip",
+        (0..=255).all(|b| Instruction::to_byte(Instruction::from_byte(b, &enc), &enc) == b),
+    );
+    let lsb = Encoding {
+        bit_order: BitOrder::Lsb,
+        ..enc
+    };
+    check(
+        "byte round-trip, lsb first",
+        (0..=255).all(|b| Instruction::to_byte(Instruction::from_byte(b, &lsb), &lsb) == b),
+    );
+
+    check("two bits per instruction", enc.bits() == 2);
+
+    let sample = b">>[+]";
+    let parsed: Result<Vec<_>, _> = Instruction::from_chars(sample.iter().copied()).collect();
+    let packed = pack(parsed.unwrap_or_default().into_iter(), &enc);
+    let unpacked: String = packed
+        .iter()
+        .flat_map(|&b| Instruction::from_byte(b, &enc))
+        .take(sample.len())
+        .join("");
+    check("parse/pack/unpack", unpacked.as_bytes() == sample);
+
+    // the cell at `[` is zero, so the body must never run
+    let mut mem = packed.clone();
+    let program_len = mem.len();
     mem.resize(256, 0);
+    let mut stats = Stats::default();
+    let result = execute_debug(
+        &mut mem,
+        program_len,
+        program_len,
+        None,
+        &Options::default(),
+        &mut stats,
+        &mut Console {
+            input: &mut io::empty(),
+            output: &mut io::sink(),
+        },
+    );
+    check(
+        "while loop skips zero cell",
+        result.is_ok() && stats.opcodes[Instruction::Increment as usize] == 0,
+    );
 
-    execute(&mut mem);
+    // the same program over a fixed array, with no Vec behind the memory
+    let mut fixed = [0u8; 256];
+    fixed[..packed.len()].copy_from_slice(&packed);
+    let mut machine = Machine::new(&mut fixed, enc, LoopMode::While);
+    let mut ok = true;
+    while ok && !machine.halted() {
+        ok = machine.advance().is_ok();
+    }
+    check("runs over a fixed array", ok && fixed[..] == mem[..]);
 
-    println!("{}", pretty_hex(&mem));
+    passed
+}
 
-    Ok(())
+// memory as a binary PGM image 16 cells wide, one grey level per cell value; a short memory
+// (--no-resize) is padded with black to fill the last row
+fn pgm(mem: &[u8]) -> Vec<u8> {
+    let height = mem.len().div_ceil(16);
+    let mut image = format!("P5\n16 {}\n255\n", height).into_bytes();
+    image.extend(mem);
+    image.resize(image.len() + height * 16 - mem.len(), 0);
+    image
 }
 
-fn execute(mem: &mut Vec<u8>) {
-    // prep registers
-    let mut dp = 0; // data pointer
-    let mut ipl = 0; // instruction pointer low
-    let mut iph = 0; // instruction pointer high
-    let mut rpl = 0; // return pointer low
-    let mut rph = 0; // return pointer high
+// compare a dump against a golden file line by line, ignoring trailing whitespace and blank lines,
+// and print a diff of the mismatching lines
+fn compare_golden(actual: &str, expected: &str) -> bool {
+    let normalize = |s: &str| -> Vec<String> {
+        s.lines()
+            .map(|l| l.trim_end().to_string())
+            .filter(|l| !l.is_empty())
+            .collect()
+    };
+    let actual = normalize(actual);
+    let expected = normalize(expected);
 
-    loop {
-        if ipl == 256 {
-            return;
-        }
-        let ins = Instruction::from_byte(mem[ipl]);
-
-        loop {
-            let inst = ins[iph];
-            match inst {
-                Instruction::LoopOpen => {
-                    rpl = ipl;
-                    rph = iph + 1;
-                    if rph > 3 {
-                        rph = 0;
-                        rpl += 1;
+    let mut matched = true;
+    for i in 0..actual.len().max(expected.len()) {
+        let (a, e) = (actual.get(i), expected.get(i));
+        if a != e {
+            matched = false;
+            if let Some(e) = e {
+                eprintln!("- {}", e);
+            }
+            if let Some(a) = a {
+                eprintln!("+ {}", a);
+            }
+        }
+    }
+    matched
+}
+
+struct Options {
+    path: String,
+    other_paths: Vec<String>, // further files, for commands that take more than one
+    step: bool,               // start in the step debugger
+    breakpoints: Vec<usize>,  // flattened instruction indices to pause at
+    max_steps: Option<usize>,
+    tape_dump_on_error: bool,
+    golden: Option<String>, // compare the final dump against this file instead of printing it
+    stats_json: Option<String>, // file to write the run summary to, or "-" for stderr
+    encoding: Encoding,
+    trace: bool,                         // print every instruction as it executes
+    trace_cells: Option<(usize, usize)>, // window of cells appended to each trace record
+    protect: Vec<(usize, usize)>,        // read-only cell ranges
+    tape_fill: u8,                       // initial value of the cells after the program
+    out_tape: Option<String>,            // file to write the final memory to
+    out_tape_trim: bool,                 // leave untouched trailing cells out of out_tape
+    loop_mode: LoopMode,
+    ip_trace: Option<String>, // file to write the sequence of visited instruction indices to
+    trace_golden: Option<String>, // file holding the trace to check against
+    record_trace: Option<String>, // file to record the trace to, for trace_golden
+    repeat: usize, // runs against the same memory; --max-steps counts steps across all of them
+    emit: Emit,
+    format: Format,                 // how the program file is written
+    encoding_info: bool,            // `ce info --encoding`
+    trace_relative: bool, // show dp relative to its value at the start of the loop iteration
+    track_uninit: bool,   // fail on incrementing a cell outside the loaded program
+    debug_on_interrupt: bool, // enter the step debugger on SIGINT instead of exiting
+    step_delay: Option<Duration>, // pacing between instructions
+    warn_self_modify: bool, // warn when an increment writes into the loaded program
+    dp_start: usize,      // initial data pointer
+    tui: bool,            // redraw a live view of memory and registers after every step
+    no_resize: bool,      // keep memory at the program's own length
+    macros: bool,         // expand repeat counts like `+{5}` and `(+>){3}` before packing
+    dump_interval: Option<usize>, // steps between periodic memory dumps
+    export_image: Option<String>, // file to write the final memory to as a PGM image
+    tape_file: Option<String>, // initial contents of the cells after the program
+    ring: bool,           // wrap ip back to 0 after the program's last instruction
+    time_opcodes: bool,   // report the average time spent executing each instruction
+    expect: Option<String>, // file the cells after the program must match after the run
+    dump_brackets: bool,  // print which `]` closes each `[` before running
+    random_tapes: usize,  // extra random inputs for `ce equiv`
+    max_dp: Option<usize>, // highest cell dp may move to, below the end of memory
+    run_until: Option<(usize, u8)>, // cell and value that stop the run once the cell is set to it
+    fail_on_warning: bool, // exit non-zero when any warning was printed
+    halt_on_stable: bool, // stop at the end of a --repeat run or --ring pass that changed nothing
+    interactive: bool,    // stdin is a terminal the step prompt can read from
+}
+
+// how a program file is written
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Source, // `[]+>` text
+    Bytes,  // hex bytes, see decode_bytes
+}
+
+// output format for `ce assemble`
+#[derive(Clone, Copy)]
+enum Emit {
+    Hex,
+    C,
+    Rust,
+}
+
+impl Emit {
+    fn format(self, bytes: &[u8]) -> String {
+        let body = bytes
+            .chunks(12)
+            .map(|c| {
+                format!(
+                    "    {},\n",
+                    c.iter().map(|b| format!("0x{:02x}", b)).join(", ")
+                )
+            })
+            .join("");
+        match self {
+            Emit::Hex => format!("{:02x}\n", bytes.iter().format(" ")),
+            Emit::C => format!("unsigned char program[{}] = {{\n{}}};\n", bytes.len(), body),
+            Emit::Rust => format!("const PROGRAM: [u8; {}] = [\n{}];\n", bytes.len(), body),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            path: String::new(),
+            other_paths: Vec::new(),
+            step: false,
+            breakpoints: Vec::new(),
+            max_steps: None,
+            tape_dump_on_error: false,
+            golden: None,
+            stats_json: None,
+            encoding: Encoding::default(),
+            trace: false,
+            trace_cells: None,
+            protect: Vec::new(),
+            tape_fill: 0,
+            out_tape: None,
+            out_tape_trim: false,
+            loop_mode: LoopMode::While,
+            ip_trace: None,
+            trace_golden: None,
+            record_trace: None,
+            repeat: 1,
+            emit: Emit::Hex,
+            format: Format::Source,
+            encoding_info: false,
+            trace_relative: false,
+            track_uninit: false,
+            debug_on_interrupt: false,
+            step_delay: None,
+            warn_self_modify: false,
+            dp_start: 0,
+            tui: false,
+            no_resize: false,
+            macros: false,
+            dump_interval: None,
+            export_image: None,
+            ring: false,
+            time_opcodes: false,
+            expect: None,
+            dump_brackets: false,
+            random_tapes: 0,
+            max_dp: None,
+            run_until: None,
+            fail_on_warning: false,
+            halt_on_stable: false,
+            interactive: false,
+            tape_file: None,
+        }
+    }
+}
+
+impl Options {
+    // `files` is how many file arguments the command takes, and `accepted` its options
+    fn parse(args: &[String], files: usize, accepted: &[&str]) -> Result<Options, CeError> {
+        let mut opts = Options::default();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let option = arg.as_str();
+            let name = option.split('=').next().unwrap_or_default();
+            if option.starts_with("--") && !accepted.contains(&name) {
+                return Err(CeError::UnknownOption(option.to_string()));
+            }
+            let mut value = |expected| next_value(&mut args, option, expected);
+            match option {
+                "--step" => opts.step = true,
+                "--break" => {
+                    let index = value("an instruction index")?;
+                    opts.breakpoints.push(parse_value(option, index)?);
+                }
+                "--max-steps" => {
+                    let n = value("a step count")?;
+                    opts.max_steps = Some(parse_value(option, n)?);
+                }
+                "--tape-dump-on-error" => opts.tape_dump_on_error = true,
+                "--golden" => opts.golden = Some(value("a file")?.to_string()),
+                "--stats-json" => opts.stats_json = Some(value("a file or '-'")?.to_string()),
+                "--opcode-map" => {
+                    opts.encoding.opcodes = Encoding::parse(value("a map")?)?.opcodes;
+                }
+                "--trace" => opts.trace = true,
+                "--trace-relative" => opts.trace_relative = true,
+                "--track-uninit" => opts.track_uninit = true,
+                "--debug-on-interrupt" => opts.debug_on_interrupt = true,
+                "--warn-self-modify" => opts.warn_self_modify = true,
+                "--fail-on-warning" => opts.fail_on_warning = true,
+                "--tui" => opts.tui = true,
+                "--ring" => opts.ring = true,
+                "--halt-on-stable" => opts.halt_on_stable = true,
+                "--dump-brackets" => opts.dump_brackets = true,
+                "--max-dp" => opts.max_dp = Some(parse_value(option, value("a cell address")?)?),
+                "--run-until" => {
+                    let watch = value("cell=ADDR,value=N")?;
+                    opts.run_until = Some(parse_watch(option, watch)?);
+                }
+                "--random" => opts.random_tapes = parse_value(option, value("a tape count")?)?,
+                "--time-opcodes" => opts.time_opcodes = true,
+                "--no-resize" => opts.no_resize = true,
+                "--macros" => opts.macros = true,
+                "--dump-interval" => {
+                    let n = value("a step count")?;
+                    match parse_value(option, n)? {
+                        0 => return Err(invalid_value(option, n)),
+                        n => opts.dump_interval = Some(n),
                     }
                 }
-                Instruction::LoopClose => {
-                    if mem[dp] != 0 {
-                        ipl = rpl;
-                        iph = rph;
-                        break;
+                "--trace-cells" => {
+                    // the window is optional and defaults to the whole tape
+                    let window = args.as_slice().first().filter(|w| w.contains(':'));
+                    opts.trace_cells = match window {
+                        Some(window) => {
+                            args.next();
+                            Some(parse_range(option, window)?)
+                        }
+                        None => Some((0, usize::MAX)),
+                    };
+                }
+                "--protect" => {
+                    let range = value("START:END")?;
+                    opts.protect.push(parse_range(option, range)?);
+                }
+                "--dp-start" => opts.dp_start = parse_value(option, value("a cell address")?)?,
+                "--tape-fill" => opts.tape_fill = parse_byte(option, value("a byte value")?)?,
+                "--out-tape" => opts.out_tape = Some(value("a file")?.to_string()),
+                "--out-tape-trim" => opts.out_tape_trim = true,
+                "--tape-file" | "--in" | "--over" => {
+                    opts.tape_file = Some(value("a file")?.to_string())
+                }
+                "--expect" => opts.expect = Some(value("a file")?.to_string()),
+                "--export-image" => opts.export_image = Some(value("a file")?.to_string()),
+                "--bit-order=msb" => opts.encoding.bit_order = BitOrder::Msb,
+                "--bit-order=lsb" => opts.encoding.bit_order = BitOrder::Lsb,
+                "--loop=while" => opts.loop_mode = LoopMode::While,
+                "--loop=do-while" => opts.loop_mode = LoopMode::DoWhile,
+                "--ip-trace" => opts.ip_trace = Some(value("a file")?.to_string()),
+                "--trace-golden" => opts.trace_golden = Some(value("a file")?.to_string()),
+                "--record-trace" => opts.record_trace = Some(value("a file")?.to_string()),
+                "--repeat" => {
+                    let n = value("a run count")?;
+                    match parse_value(option, n)? {
+                        0 => return Err(invalid_value(option, n)),
+                        n => opts.repeat = n,
                     }
                 }
-                Instruction::Increment => {
-                    mem[dp] += 1;
+                "--format=source" => opts.format = Format::Source,
+                "--format=bytes" => opts.format = Format::Bytes,
+                "--encoding" => opts.encoding_info = true,
+                "--emit=hex" => opts.emit = Emit::Hex,
+                "--emit=c" => opts.emit = Emit::C,
+                "--emit=rust" => opts.emit = Emit::Rust,
+                "--step-delay" => {
+                    let ms = parse_value(option, value("milliseconds")?)?;
+                    opts.step_delay = Some(Duration::from_millis(ms));
                 }
-                Instruction::ShiftRight => {
-                    dp += 1;
+                _ if option.starts_with("--") => {
+                    return Err(CeError::UnknownOption(option.to_string()))
                 }
+                _ if opts.path.is_empty() => opts.path = arg.clone(),
+                _ => opts.other_paths.push(arg.clone()),
             }
+        }
+
+        if opts.trace_cells.is_some() && !opts.trace && !opts.keeps_trace() {
+            return Err(CeError::OptionRequires {
+                option: "--trace-cells".to_string(),
+                requires: "--trace, --trace-golden or --record-trace",
+            });
+        }
+
+        if opts.halt_on_stable && opts.repeat < 2 && !opts.ring {
+            return Err(CeError::OptionRequires {
+                option: "--halt-on-stable".to_string(),
+                requires: "--repeat or --ring",
+            });
+        }
+
+        if opts.path.is_empty() || opts.other_paths.len() + 1 < files {
+            return Err(CeError::Usage);
+        }
+        if let Some(extra) = opts.other_paths.get(files - 1) {
+            return Err(CeError::UnexpectedArgument(extra.clone()));
+        }
+
+        Ok(opts)
+    }
+}
+
+// the argument after `option`, which should be `expected`
+fn next_value<'a>(
+    args: &mut std::slice::Iter<'a, String>,
+    option: &str,
+    expected: &'static str,
+) -> Result<&'a str, CeError> {
+    match args.next() {
+        Some(value) => Ok(value),
+        None => Err(CeError::MissingValue {
+            option: option.to_string(),
+            expected,
+        }),
+    }
+}
+
+fn invalid_value(option: &str, value: &str) -> CeError {
+    CeError::InvalidValue {
+        option: option.to_string(),
+        value: value.to_string(),
+    }
+}
+
+// parse a decimal option value
+fn parse_value<T: FromStr>(option: &str, value: &str) -> Result<T, CeError> {
+    value.parse().map_err(|_| invalid_value(option, value))
+}
+
+// parse a `START:END` cell range, END exclusive
+fn parse_range(option: &str, range: &str) -> Result<(usize, usize), CeError> {
+    match range.split_once(':').map(|(s, e)| (s.parse(), e.parse())) {
+        Some((Ok(start), Ok(end))) if start <= end => Ok((start, end)),
+        _ => Err(invalid_value(option, range)),
+    }
+}
+
+// parse a byte given in decimal or as 0x-prefixed hex
+fn parse_byte(option: &str, byte: &str) -> Result<u8, CeError> {
+    let value = match byte.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => byte.parse(),
+    };
+    value.map_err(|_| invalid_value(option, byte))
+}
+
+// parse a `cell=ADDR,value=N` data breakpoint
+fn parse_watch(option: &str, watch: &str) -> Result<(usize, u8), CeError> {
+    let fields = watch
+        .split_once(',')
+        .and_then(|(c, v)| Some((c.strip_prefix("cell=")?, v.strip_prefix("value=")?)));
+    match fields {
+        Some((cell, value)) => match (cell.parse(), parse_byte(option, value)) {
+            (Ok(cell), Ok(value)) => Ok((cell, value)),
+            _ => Err(invalid_value(option, watch)),
+        },
+        None => Err(invalid_value(option, watch)),
+    }
+}
+
+impl Options {
+    // whether any option needs the per-step hooks in execute_debug
+    fn instrumented(&self) -> bool {
+        self.step
+            || !self.breakpoints.is_empty()
+            || self.max_steps.is_some()
+            || self.stats_json.is_some()
+            || self.trace
+            || !self.protect.is_empty()
+            || self.ip_trace.is_some()
+            || self.keeps_trace()
+            || self.track_uninit
+            || self.debug_on_interrupt
+            || self.step_delay.is_some()
+            || self.warn_self_modify
+            || self.tui
+            || self.dump_interval.is_some()
+            || self.ring
+            || self.time_opcodes
+            || self.max_dp.is_some()
+            || self.run_until.is_some()
+    }
+
+    // whether the trace records are kept in Stats, to check or record once the run ends
+    fn keeps_trace(&self) -> bool {
+        self.trace_golden.is_some() || self.record_trace.is_some()
+    }
+}
+
+// counters collected while executing, for the run summary
+#[derive(Default)]
+struct Stats {
+    steps: usize,
+    opcodes: [usize; 4], // executions per instruction, indexed by discriminant
+    dp: usize,
+    max_dp: usize,
+    path: Vec<usize>, // every instruction index dispatched, only kept for --ip-trace
+    touched: HashSet<usize>, // distinct cells written
+    opcode_time: [Duration; 4], // time spent executing each instruction, for --time-opcodes
+    watch_hit: Option<usize>, // the step at which --run-until fired
+    trace: Vec<String>, // every trace record, only kept for --trace-golden and --record-trace
+}
 
-            iph += 1;
-            if iph > 3 {
-                iph = 0;
-                ipl += 1;
-                break;
+impl Stats {
+    // average time per execution of each instruction that ran, for --time-opcodes
+    fn opcode_report(&self) -> String {
+        Instruction::ALL
+            .iter()
+            .filter(|&&i| self.opcodes[i as usize] > 0)
+            .map(|&i| {
+                let count = self.opcodes[i as usize];
+                let nanos = self.opcode_time[i as usize].as_nanos() / count as u128;
+                format!("{} {:>8} ns/op {:>12} executions\n", i, nanos, count)
+            })
+            .join("")
+    }
+
+    fn to_json(&self, elapsed: Duration, error: Option<&ExecError>) -> String {
+        let opcodes = Instruction::ALL
+            .iter()
+            .map(|&i| format!("\"{}\":{}", i, self.opcodes[i as usize]))
+            .join(",");
+        let status = status(error);
+        format!(
+            "{{\"steps\":{},\"opcodes\":{{{}}},\"dp\":{},\"max_dp\":{},\"cells_touched\":{},\"elapsed_us\":{},\"status\":\"{}\"}}",
+            self.steps,
+            opcodes,
+            self.dp,
+            self.max_dp,
+            self.touched.len(),
+            elapsed.as_micros(),
+            status
+        )
+    }
+}
+
+// how a run ended, as reported in the run summary
+fn status(error: Option<&ExecError>) -> &'static str {
+    match error {
+        None => "halted",
+        Some(ExecError::DataPointerOutOfBounds { .. }) => "out_of_bounds",
+        Some(ExecError::StepLimitExceeded { .. }) => "step_limit",
+        Some(ExecError::ProtectedWrite { .. }) => "protected_write",
+        Some(ExecError::UninitializedCell { .. }) => "uninitialized_cell",
+        Some(ExecError::MaxDpExceeded { .. }) => "max_dp",
+        Some(ExecError::Quit { .. }) => "quit",
+    }
+}
+
+// the interpreter without any per-step instrumentation, used when no debugging or profiling
+// options are given; must behave identically to execute_debug
+fn execute_fast(mem: &mut [u8], opts: &Options) -> Result<(), ExecError> {
+    let enc = &opts.encoding;
+    let mut dp = opts.dp_start;
+    let mut ip = 0;
+    let mut rp = 0;
+    let mut byte = None;
+
+    while ip < mem.len() * 4 {
+        match Instruction::nth(*byte.get_or_insert(mem[ip / 4]), ip % 4, enc) {
+            Instruction::LoopOpen => {
+                if opts.loop_mode == LoopMode::While && mem[dp] == 0 {
+                    ip = loop_exit(mem, ip, enc);
+                    byte = None;
+                    continue;
+                }
+                rp = ip + 1;
+            }
+            Instruction::LoopClose => {
+                if mem[dp] != 0 {
+                    ip = rp;
+                    byte = None;
+                    continue;
+                }
+            }
+            Instruction::Increment => {
+                mem[dp] = mem[dp].wrapping_add(1);
+            }
+            Instruction::ShiftRight => {
+                if dp + 1 >= mem.len() {
+                    return Err(ExecError::DataPointerOutOfBounds { ip });
+                }
+                dp += 1;
             }
         }
+
+        ip += 1;
+        if ip % 4 == 0 {
+            byte = None;
+        }
+    }
+
+    Ok(())
+}
+
+// run the program --repeat times against the same memory, stopping early on an error, a
+// --run-until hit with no one to prompt, or a --halt-on-stable run that changed nothing
+fn execute_runs(
+    mem: &mut Vec<u8>,
+    program_len: usize,
+    loaded_len: usize,
+    ring: Option<usize>,
+    opts: &Options,
+    stats: &mut Stats,
+    console: &mut Console,
+) -> Result<(), ExecError> {
+    if opts.tui {
+        // switch to the alternate screen so the final dump lands on the normal one
+        write!(console.output, "\x1b[?1049h\x1b[?25l").ok();
+    }
+    let mut result = Ok(());
+    for _ in 0..opts.repeat {
+        let before = opts.halt_on_stable.then(|| mem.clone());
+        result = if opts.instrumented() {
+            execute_debug(mem, program_len, loaded_len, ring, opts, stats, console)
+        } else {
+            execute_fast(mem, opts)
+        };
+        let watch_stopped = stats.watch_hit.is_some() && !opts.interactive;
+        if result.is_err() || watch_stopped || before.is_some_and(|before| before == *mem) {
+            break;
+        }
+    }
+    if opts.tui {
+        write!(console.output, "\x1b[?25h\x1b[?1049l").ok();
+        console.output.flush().ok();
     }
+    result
 }
 
-#[derive(Copy, Clone)]
-enum Instruction {
-    LoopOpen,
-    LoopClose,
-    Increment,
-    ShiftRight,
+// number of --dump-interval files kept before the oldest is overwritten
+const DUMP_FILES: usize = 10;
+
+// --step-delay schedules instruction `n` at a fixed offset from the start of the run, not from
+// the instruction before it, so time spent executing (or paused at the prompt) is absorbed
+// instead of accumulating as drift
+fn due(start: Instant, delay: Duration, n: u32) -> Instant {
+    start + delay * n
 }
 
-use std::fmt;
-impl fmt::Display for Instruction {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Instruction::LoopOpen => '[',
-                Instruction::LoopClose => ']',
-                Instruction::Increment => '+',
-                Instruction::ShiftRight => '>',
+// `program_len` is the number of bytes the program was loaded into, and `loaded_len` also
+// counts the --tape-file bytes loaded after it; `ring` is the instruction count for --ring
+fn execute_debug(
+    mem: &mut [u8],
+    program_len: usize,
+    loaded_len: usize,
+    ring: Option<usize>,
+    opts: &Options,
+    stats: &mut Stats,
+    console: &mut Console,
+) -> Result<(), ExecError> {
+    // which cells have been given a value, for --track-uninit
+    let init: Vec<bool> = (0..mem.len()).map(|addr| addr < loaded_len).collect();
+
+    let mut machine = Machine::new(mem, opts.encoding, opts.loop_mode).with_dp(opts.dp_start);
+    if let Some(len) = ring {
+        machine = machine.with_ring(len);
+    }
+    let mut stepping = opts.step;
+    let mut tui_live = opts.tui;
+    let mut iterations = 0; // jumps back by `]` since the last `[` was entered
+    let mut pass_start = ring
+        .filter(|_| opts.halt_on_stable)
+        .map(|_| (machine.mem.to_vec(), machine.dp()));
+
+    // when --step-delay pacing started, for due()
+    let start = Instant::now();
+    let mut scheduled: u32 = 0;
+
+    while let Some(inst) = machine.current() {
+        let (ip, dp) = (machine.ip(), machine.dp());
+
+        if opts.max_steps.is_some_and(|max| stats.steps >= max) {
+            return Err(ExecError::StepLimitExceeded {
+                steps: stats.steps,
+                ip,
+                // rp is one past the `[` that set it, and 0 before any loop is entered
+                loop_start: machine.rp().checked_sub(1),
+                iterations,
+            });
+        }
+        stats.steps += 1;
+        stats.opcodes[inst as usize] += 1;
+        if opts.ip_trace.is_some() {
+            stats.path.push(ip);
+        }
+
+        if let Some(delay) = opts.step_delay {
+            thread::sleep(due(start, delay, scheduled).saturating_duration_since(Instant::now()));
+            scheduled += 1;
+        }
+
+        // pause before dispatch if single-stepping, interrupted or a breakpoint is hit
+        if opts.debug_on_interrupt && INTERRUPTED.swap(false, Ordering::SeqCst) {
+            stepping = true;
+        }
+        if opts.breakpoints.contains(&ip) {
+            stepping = true;
+        }
+        let base = opts.trace_relative.then_some(machine.loop_dp());
+        if opts.trace || opts.keeps_trace() {
+            let mem = &machine.mem;
+            let mut record = state_line(ip, inst, dp, base, mem);
+            if let Some((start, end)) = opts.trace_cells {
+                let (start, end) = (start.min(mem.len()), end.min(mem.len()));
+                record += &format!(
+                    " cells[{:02x}..{:02x}]={:02x}",
+                    start,
+                    end,
+                    mem[start..end].iter().format(" ")
+                );
             }
-        )
+            if opts.trace {
+                eprintln!("{}", record);
+            }
+            if opts.keeps_trace() {
+                stats.trace.push(record);
+            }
+        }
+        if stepping {
+            if !opts.trace {
+                eprintln!("{}", state_line(ip, inst, dp, base, machine.mem));
+            }
+            match step_prompt(&machine, &mut console.input) {
+                StepCommand::Step => {}
+                StepCommand::Continue => stepping = false,
+                StepCommand::Quit => return Err(ExecError::Quit { ip }),
+            }
+        }
+        if tui_live {
+            write!(console.output, "{}", tui_frame(&machine, inst, stats.steps)).ok();
+            console.output.flush().ok();
+            // advance on enter unless --step-delay paces the view
+            if opts.step_delay.is_none() {
+                tui_live = tui_prompt(&mut console.input);
+            }
+        }
+
+        if inst == Instruction::ShiftRight && opts.max_dp.is_some_and(|max| dp + 1 > max) {
+            return Err(ExecError::MaxDpExceeded { dp: dp + 1, ip });
+        }
+        if inst == Instruction::Increment {
+            if opts
+                .protect
+                .iter()
+                .any(|&(start, end)| (start..end).contains(&dp))
+            {
+                return Err(ExecError::ProtectedWrite { addr: dp, ip });
+            }
+            if opts.track_uninit && !init[dp] {
+                return Err(ExecError::UninitializedCell { addr: dp, ip });
+            }
+            stats.touched.insert(dp);
+            if opts.warn_self_modify && dp < program_len {
+                warn(format!(
+                    "instruction {} modifies the program at cell {:02x}",
+                    ip, dp
+                ));
+            }
+        }
+
+        match inst {
+            Instruction::LoopOpen
+                if opts.loop_mode == LoopMode::DoWhile || machine.mem[dp] != 0 =>
+            {
+                iterations = 0
+            }
+            Instruction::LoopClose if machine.mem[dp] != 0 => iterations += 1,
+            _ => {}
+        }
+
+        if opts.time_opcodes {
+            let started = Instant::now();
+            machine.advance()?;
+            stats.opcode_time[inst as usize] += started.elapsed();
+        } else {
+            machine.advance()?;
+        }
+        stats.dp = machine.dp();
+        stats.max_dp = stats.max_dp.max(stats.dp);
+
+        // --run-until fires once, after the write that first sets the cell to the value: it
+        // pauses at the step prompt, or without a terminal to prompt on, ends the run there
+        if let Some((addr, value)) = opts.run_until {
+            let hit = inst == Instruction::Increment && dp == addr && machine.mem[addr] == value;
+            if hit && stats.watch_hit.is_none() {
+                stats.watch_hit = Some(stats.steps);
+                eprintln!(
+                    "cell {:02x} reached {:02x} at step {}",
+                    addr, value, stats.steps
+                );
+                if !opts.interactive {
+                    break;
+                }
+                stepping = true;
+            }
+        }
+
+        // a --ring pass ends each time ip wraps to 0, and the program has reached a fixed point
+        // once a pass leaves both memory and dp as it found them
+        if let Some((start, start_dp)) = &mut pass_start {
+            if machine.ip() == 0 {
+                if *start == *machine.mem && *start_dp == machine.dp() {
+                    break;
+                }
+                start.copy_from_slice(machine.mem);
+                *start_dp = machine.dp();
+            }
+        }
+
+        // every --dump-interval steps, write memory to `<program>.dump.<n>`, reusing the
+        // DUMP_FILES names in turn so a long run keeps only the latest few
+        if let Some(interval) = opts.dump_interval {
+            if stats.steps.is_multiple_of(interval) {
+                let n = stats.steps / interval - 1;
+                let path = format!("{}.dump.{}", opts.path, n % DUMP_FILES);
+                if let Err(e) = fs::write(&path, &machine.mem) {
+                    warn(format!("could not write {}: {}", path, e));
+                }
+            }
+        }
     }
+
+    Ok(())
 }
 
-impl Instruction {
-    #[allow(dead_code)]
-    fn from_byte(byte: u8) -> Vec<Instruction> {
-        // split into 2-bit pairs before mapping
-        [
-            (byte & 0b11000000) >> 6,
-            (byte & 0b00110000) >> 4,
-            (byte & 0b00001100) >> 2,
-            (byte & 0b00000011),
-        ]
-        .into_iter()
-        .map(|b| match b {
-            0b00 => Instruction::LoopOpen,
-            0b01 => Instruction::LoopClose,
-            0b10 => Instruction::Increment,
-            0b11 => Instruction::ShiftRight,
-            _ => panic!("if you got here, you invented a new type of number"),
-        })
-        .collect()
+// registers and current instruction, shown by the step debugger and in trace records
+// with a `base`, dp is shown as an offset from it
+fn state_line(ip: usize, inst: Instruction, dp: usize, base: Option<usize>, mem: &[u8]) -> String {
+    let dp_text = match base {
+        Some(base) => format!("dp=+{:02x} mem[+{:02x}]", dp - base, dp - base),
+        None => format!("dp={:02x} mem[dp]", dp),
+    };
+    format!(
+        "[{}] {} ip={:02x}:{} {}={:02x}",
+        ip,
+        inst,
+        ip / 4,
+        ip % 4,
+        dp_text,
+        mem[dp]
+    )
+}
+
+// counts the warnings printed so far, for --fail-on-warning
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+fn warn(message: String) {
+    eprintln!("Warning: {}", message);
+    WARNINGS.fetch_add(1, Ordering::SeqCst);
+}
+
+// set by the SIGINT handler installed for --debug-on-interrupt
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn install_interrupt_handler() {
+    const SIGINT: i32 = 2;
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+    extern "C" fn handle(_: i32) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        signal(SIGINT, handle);
+    }
+}
+
+#[cfg(not(unix))]
+fn install_interrupt_handler() {}
+
+// the terminal the step prompt reads commands from and the --tui view is drawn on
+struct Console<'a> {
+    input: &'a mut dyn BufRead,
+    output: &'a mut dyn Write,
+}
+
+// one screen of the --tui view: registers, then memory as a 16x16 grid with the cell under
+// dp in reverse video and the byte being executed underlined
+fn tui_frame(machine: &Machine, inst: Instruction, steps: usize) -> String {
+    let state = machine.state();
+    let mut frame = format!(
+        "\x1b[H\x1b[2Jstep {}  {}  ip={:02x}:{}  dp={:02x}  rp={:02x}:{}  mem[dp]={:02x}\n\n",
+        steps,
+        inst,
+        state.ip / 4,
+        state.ip % 4,
+        state.dp,
+        state.rp / 4,
+        state.rp % 4,
+        state.mem[state.dp]
+    );
+    for (row, cells) in state.mem.chunks(16).enumerate() {
+        frame += &format!("{:02x}: ", row * 16);
+        for (col, cell) in cells.iter().enumerate() {
+            let addr = row * 16 + col;
+            let style = match (addr == state.dp, addr == state.ip / 4) {
+                (true, true) => "\x1b[7;4m",
+                (true, false) => "\x1b[7m",
+                (false, true) => "\x1b[4m",
+                (false, false) => "",
+            };
+            frame += &match style {
+                "" => format!(" {:02x}", cell),
+                _ => format!(" {}{:02x}\x1b[0m", style, cell),
+            };
+        }
+        frame += "\n";
     }
+    frame + "\nenter: step  q: run to the end\n"
+}
 
-    fn from_chars(chars: impl Iterator<Item = u8>) -> impl Iterator<Item = Instruction> {
-        chars.map(|c| match c {
-            b'[' => Instruction::LoopOpen,
-            b']' => Instruction::LoopClose,
-            b'+' => Instruction::Increment,
-            b'>' => Instruction::ShiftRight,
-            _ => panic!("Invalid instruction '{}'", c),
-        })
+// wait for enter before the next --tui frame; false once the user asks to run the rest of the
+// program without the view
+fn tui_prompt(input: &mut impl BufRead) -> bool {
+    let mut line = String::new();
+    match input.read_line(&mut line) {
+        Ok(n) if n > 0 => line.trim() != "q",
+        _ => false,
     }
+}
 
-    fn to_pair(i: Option<&Instruction>) -> u8 {
-        match i {
-            Some(Instruction::LoopOpen) => 0b00,
-            Some(Instruction::LoopClose) => 0b01,
-            Some(Instruction::Increment) => 0b10,
-            Some(Instruction::ShiftRight) => 0b11,
-            None => 0b00,
+#[derive(Debug, PartialEq)]
+enum StepCommand {
+    Step,
+    Continue,
+    Quit,
+}
+
+// read debugger commands until one resumes execution: enter or `s` steps, `c` continues to
+// the next breakpoint (as does end of input), `q` quits, and `r` and `d` print the registers or memory
+fn step_prompt(machine: &Machine, input: &mut impl BufRead) -> StepCommand {
+    loop {
+        eprint!("step> ");
+        io::stderr().flush().ok();
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(n) if n > 0 => {}
+            _ => return StepCommand::Continue,
+        }
+        match line.trim() {
+            "" | "s" => return StepCommand::Step,
+            "c" => return StepCommand::Continue,
+            "q" => return StepCommand::Quit,
+            "r" => {
+                let state = machine.state();
+                eprintln!(
+                    "ip={:02x}:{} dp={:02x} rp={:02x}:{} mem[dp]={:02x}",
+                    state.ip / 4,
+                    state.ip % 4,
+                    state.dp,
+                    state.rp / 4,
+                    state.rp % 4,
+                    state.mem[state.dp]
+                );
+            }
+            "d" => eprintln!("{}", pretty_hex(&machine.mem)),
+            other => eprintln!("unknown command '{}', expected s, c, r, d or q", other),
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    // write a file under the temp dir, named for this test process and `name`
+    fn temp_file(name: &str, contents: &[u8]) -> String {
+        let path = env::temp_dir().join(format!("ce-test-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    // `ce run` on `src`, saved as `name`, returning the result and the memory --out-tape wrote
+    fn run_to_tape(name: &str, src: &str, line: &str) -> (Result<(), CeError>, Vec<u8>) {
+        let program = temp_file(name, src.as_bytes());
+        let tape = format!("{}.out", program);
+        let _ = fs::remove_file(&tape);
+        let result = run_program(
+            &args(&format!("{} --out-tape {} {}", program, tape, line)),
+            false,
+        );
+        (result, fs::read(&tape).unwrap_or_default())
+    }
+
+    // options for a program file, as `ce run p.ce <line>` would parse them
+    fn opts(line: &str) -> Options {
+        Options::parse(&args(&format!("p.ce {}", line)), 1, RUN_OPTIONS).unwrap()
+    }
+
+    // parse source and pack it into 256 bytes of memory, the way `ce run` loads a program
+    fn load(src: &str) -> Vec<u8> {
+        load_with(src, &Options::default())
+    }
+
+    // the same, under the encoding and --tape-fill in `opts`
+    fn load_with(src: &str, opts: &Options) -> Vec<u8> {
+        let instructions: Vec<_> = Instruction::from_chars(src.bytes())
+            .map(Result::unwrap)
+            .collect();
+        let mut mem = pack(instructions.into_iter(), &opts.encoding);
+        mem.resize(256, opts.tape_fill);
+        mem
+    }
+
+    // run `src` through the debug interpreter under the options in `line`
+    // under a default step limit, so a program that loops forever fails instead of hanging
+    fn debug_run(src: &str, line: &str) -> (Vec<u8>, Result<(), ExecError>, Stats) {
+        prompted_run(src, line, "")
+    }
+
+    // the same, answering the step prompt from `input`
+    fn prompted_run(src: &str, line: &str, input: &str) -> (Vec<u8>, Result<(), ExecError>, Stats) {
+        let opts = opts(&format!("--max-steps 1000000 {}", line));
+        let mut mem = load_with(src, &opts);
+        let program_len = src.len().div_ceil(4);
+        let ring = opts.ring.then_some(src.len());
+        let mut stats = Stats::default();
+        let result = execute_debug(
+            &mut mem,
+            program_len,
+            program_len,
+            ring,
+            &opts,
+            &mut stats,
+            &mut Console {
+                input: &mut Cursor::new(input),
+                output: &mut io::sink(),
+            },
+        );
+        (mem, result, stats)
+    }
+
+    #[test]
+    fn step_prompt_steps_on_enter_or_s() {
+        let mut mem = load("+>");
+        let machine = Machine::new(&mut mem, Encoding::default(), LoopMode::While);
+        assert_eq!(
+            step_prompt(&machine, &mut Cursor::new("\n")),
+            StepCommand::Step
+        );
+        assert_eq!(
+            step_prompt(&machine, &mut Cursor::new("s\n")),
+            StepCommand::Step
+        );
+    }
+
+    #[test]
+    fn step_prompt_continues_at_end_of_input() {
+        let mut mem = load("+>");
+        let machine = Machine::new(&mut mem, Encoding::default(), LoopMode::While);
+        assert_eq!(
+            step_prompt(&machine, &mut Cursor::new("")),
+            StepCommand::Continue
+        );
+    }
+
+    #[test]
+    fn break_takes_instruction_indices() {
+        let opts = Options::parse(&args("p.ce --break 3 --break 10"), 1, RUN_OPTIONS).unwrap();
+        assert_eq!(opts.breakpoints, [3, 10]);
+        assert!(opts.instrumented());
+        assert!(Options::parse(&args("p.ce --break x"), 1, RUN_OPTIONS).is_err());
+    }
+
+    #[test]
+    fn break_pauses_before_the_instruction_at_its_index() {
+        // quitting at the first prompt shows where it ran
+        let (mem, result, _) = prompted_run("+>+>", "--dp-start 100 --break 2", "q\n");
+        assert_eq!(result, Err(ExecError::Quit { ip: 2 }));
+        assert_eq!(mem[100..102], [1, 0]);
+    }
+
+    #[test]
+    fn moving_past_the_end_is_an_error_naming_the_instruction() {
+        let opts = opts("--dp-start 255");
+        let mut mem = load("+>");
+        let fast = execute_fast(&mut mem, &opts);
+        assert_eq!(fast, Err(ExecError::DataPointerOutOfBounds { ip: 1 }));
+
+        let mut mem = load("+>");
+        let debug = execute_debug(
+            &mut mem,
+            1,
+            1,
+            None,
+            &opts,
+            &mut Stats::default(),
+            &mut Console {
+                input: &mut io::empty(),
+                output: &mut io::sink(),
+            },
+        );
+        assert_eq!(debug, fast);
+        assert_eq!(mem[255], 1);
+    }
+
+    #[test]
+    fn shebang_line_is_skipped() {
+        let path = temp_file("shebang.ce", b"#!/usr/bin/env ce\n+>\n");
+        let program = load_program(&path, &Options::default()).unwrap();
+        assert_eq!(program, [Instruction::Increment, Instruction::ShiftRight]);
+
+        // only a leading `#!` is a shebang
+        let path = temp_file("not-shebang.ce", b"+#!\n");
+        assert!(matches!(
+            load_program(&path, &Options::default()),
+            Err(CeError::InvalidInstruction(b'#'))
+        ));
+    }
+
+    #[test]
+    fn golden_ignores_trailing_whitespace_and_blank_lines() {
+        assert!(compare_golden("a\nb\n", "a  \n\nb"));
+        assert!(!compare_golden("a\nb\n", "a\nc\n"));
+        assert!(!compare_golden("a\nb\n", "a\n"));
+    }
+
+    #[test]
+    fn golden_compares_the_final_dump() {
+        let program = temp_file("golden.ce", b"+\n");
+        let mut mem = load("+");
+        mem[0] += 1; // the program increments its own first cell
+        let golden = temp_file("golden.txt", pretty_hex(&mem).as_bytes());
+        let stale = temp_file("golden-stale.txt", pretty_hex(&load("+")).as_bytes());
+
+        assert!(run_program(&args(&format!("{} --golden {}", program, golden)), false).is_ok());
+        let result = run_program(&args(&format!("{} --golden {}", program, stale)), false);
+        assert!(matches!(result, Err(CeError::GoldenMismatch(_))));
+    }
+
+    #[test]
+    fn stats_json_summarizes_the_run() {
+        let (_, result, stats) = debug_run(">+[+]", "--dp-start 100");
+        let json = stats.to_json(Duration::from_micros(7), result.as_ref().err());
+        assert_eq!(
+            json,
+            "{\"steps\":514,\"opcodes\":{\"[\":2,\"]\":255,\"+\":256,\">\":1},\"dp\":101,\
+             \"max_dp\":101,\"cells_touched\":1,\"elapsed_us\":7,\"status\":\"halted\"}"
+        );
+    }
+
+    #[test]
+    fn stats_json_reports_how_the_run_failed() {
+        let (_, result, stats) = debug_run("+[]", "--dp-start 100 --max-steps 50");
+        let json = stats.to_json(Duration::ZERO, result.as_ref().err());
+        assert!(json.contains("\"steps\":50,"));
+        assert!(json.ends_with("\"status\":\"step_limit\"}"));
+    }
+
+    #[test]
+    fn trace_record_shows_registers_and_the_current_cell() {
+        let mut mem = load("+>");
+        mem[5] = 0x2a;
+        let line = state_line(6, Instruction::ShiftRight, 5, None, &mem);
+        assert_eq!(line, "[6] > ip=01:2 dp=05 mem[dp]=2a");
+    }
+
+    #[test]
+    fn trace_cells_window_defaults_to_all_of_memory() {
+        assert_eq!(
+            opts("--trace --trace-cells").trace_cells,
+            Some((0, usize::MAX))
+        );
+        assert_eq!(opts("--trace --trace-cells 4:8").trace_cells, Some((4, 8)));
+        // a following argument without a colon isn't taken as the window
+        let opts = Options::parse(&args("--trace --trace-cells p.ce"), 1, RUN_OPTIONS).unwrap();
+        assert_eq!(
+            (opts.trace_cells, opts.path.as_str()),
+            (Some((0, usize::MAX)), "p.ce")
+        );
+
+        let without_trace = Options::parse(&args("p.ce --trace-cells 0:4"), 1, RUN_OPTIONS);
+        assert!(matches!(without_trace, Err(CeError::OptionRequires { .. })));
+        assert!(Options::parse(&args("p.ce --trace --trace-cells 8:4"), 1, RUN_OPTIONS).is_err());
+    }
+
+    // random programs over `[]+>`, weighted towards `+`, from a fixed-seed xorshift so a
+    // failure can be reproduced
+    fn random_programs(count: usize) -> Vec<String> {
+        let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as usize
+        };
+        (0..count)
+            .map(|_| {
+                let len = 1 + next() % 40;
+                (0..len).map(|_| b"[]++>"[next() % 5] as char).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fast_and_debug_interpreters_agree() {
+        let mut compared = 0;
+        for src in random_programs(200) {
+            for line in [
+                "",
+                "--loop=do-while",
+                "--bit-order=lsb",
+                "--dp-start 7 --tape-fill 3",
+            ] {
+                let (debug_mem, debug, _) = debug_run(&src, &format!("--max-steps 20000 {}", line));
+                // execute_fast has no step limit, so only programs that finish can be compared
+                if matches!(debug, Err(ExecError::StepLimitExceeded { .. })) {
+                    continue;
+                }
+                let opts = opts(line);
+                let mut fast_mem = load_with(&src, &opts);
+                let fast = execute_fast(&mut fast_mem, &opts);
+                assert_eq!(fast, debug, "{} {}", src, line);
+                assert_eq!(fast_mem, debug_mem, "{} {}", src, line);
+                compared += 1;
+            }
+        }
+        assert!(compared > 300, "only {} runs finished", compared);
+    }
+
+    // `cargo test --release -- --ignored --nocapture` to compare the two interpreters' speed
+    #[test]
+    #[ignore]
+    fn bench_fast_against_debug() {
+        let src = "++[+[+]>]";
+        let opts = opts("--dp-start 100");
+        let started = Instant::now();
+        for _ in 0..1000 {
+            execute_fast(&mut load(src), &opts).unwrap();
+        }
+        let fast = started.elapsed();
+        let started = Instant::now();
+        for _ in 0..1000 {
+            debug_run(src, "--dp-start 100").1.unwrap();
+        }
+        println!(
+            "fast {:?}, debug {:?} per 1000 runs",
+            fast,
+            started.elapsed()
+        );
+    }
+
+    #[test]
+    fn protected_cells_reject_writes() {
+        let (mem, result, _) = debug_run(">+", "--dp-start 99 --protect 100:101");
+        assert_eq!(result, Err(ExecError::ProtectedWrite { addr: 100, ip: 1 }));
+        assert_eq!(mem[100], 0);
+
+        // the range is exclusive at the end
+        let (_, result, _) = debug_run("++", "--dp-start 100 --protect 90:100 --protect 101:110");
+        assert_eq!(result, Ok(()));
+        assert!(Options::parse(&args("p.ce --protect 4"), 1, RUN_OPTIONS).is_err());
+    }
+
+    #[test]
+    fn tape_fill_sets_the_cells_after_the_program() {
+        assert_eq!(opts("--tape-fill 0x2a").tape_fill, 42);
+        assert_eq!(opts("--tape-fill 42").tape_fill, 42);
+        assert!(Options::parse(&args("p.ce --tape-fill 256"), 1, RUN_OPTIONS).is_err());
+
+        // 0xaa decodes as `++++`, so the filled cells run as increments of the program's own
+        // cell, which has already been executed
+        let (result, tape) = run_to_tape("fill.ce", "+\n", "--tape-fill 0xaa");
+        assert!(result.is_ok());
+        assert_eq!(tape.len(), 256);
+        assert!(tape[1..].iter().all(|&c| c == 0xaa));
+    }
+
+    #[test]
+    fn out_tape_writes_the_final_memory() {
+        // the program leaves 2 in cell 100, which then runs as `[[[+` and makes it 3
+        let (result, tape) = run_to_tape("out.ce", "++\n", "--dp-start 100");
+        assert!(result.is_ok());
+        assert_eq!(tape.len(), 256);
+        assert_eq!((tape[0], tape[100], tape[101]), (0xa0, 3, 0));
+
+        let (_, trimmed) = run_to_tape("out-trim.ce", "++\n", "--dp-start 100 --out-tape-trim");
+        assert_eq!(trimmed, tape[..101]);
+    }
+
+    #[test]
+    fn ip_trace_records_every_dispatched_instruction() {
+        // the `[` at 2 finds a zero cell and skips to the end of memory
+        let (_, _, stats) = debug_run("+>", "--dp-start 254 --ip-trace t");
+        assert_eq!(stats.path, [0, 1, 2]);
+
+        let trace = temp_file("ip-trace.txt", b"");
+        let (result, _) = run_to_tape(
+            "ip-trace.ce",
+            "+>\n",
+            &format!("--dp-start 254 --ip-trace {}", trace),
+        );
+        assert!(result.is_ok());
+        assert_eq!(fs::read_to_string(&trace).unwrap(), "0 1 2\n");
+    }
+
+    #[test]
+    fn repeat_reruns_on_the_same_memory() {
+        // each run increments cell 100, then skips the rest of memory from the zero cell after it
+        let (result, tape) = run_to_tape("repeat.ce", "+>[\n", "--dp-start 100 --repeat 3");
+        assert!(result.is_ok());
+        assert_eq!(tape[100], 3);
+
+        assert!(Options::parse(&args("p.ce --repeat 0"), 1, RUN_OPTIONS).is_err());
+    }
+
+    #[test]
+    fn selftest_passes() {
+        assert!(selftest());
+    }
+
+    #[test]
+    fn emit_formats_the_packed_bytes() {
+        let bytes = [0xa2, 0x40];
+        assert_eq!(Emit::Hex.format(&bytes), "a2 40\n");
+        assert_eq!(
+            Emit::C.format(&bytes),
+            "unsigned char program[2] = {\n    0xa2, 0x40,\n};\n"
+        );
+        assert_eq!(
+            Emit::Rust.format(&bytes),
+            "const PROGRAM: [u8; 2] = [\n    0xa2, 0x40,\n];\n"
+        );
+    }
+
+    #[test]
+    fn emit_wraps_arrays_at_twelve_bytes() {
+        let body = Emit::Rust.format(&[0; 13]);
+        let rows: Vec<&str> = body.lines().collect();
+        assert_eq!(rows.len(), 4);
+        assert_eq!(rows[1].matches("0x00").count(), 12);
+        assert_eq!(rows[2], "    0x00,");
+    }
+
+    #[test]
+    fn trace_relative_shows_dp_from_the_loop_start() {
+        let mem = load("+>");
+        let line = state_line(6, Instruction::Increment, 6, Some(4), &mem);
+        assert_eq!(line, "[6] + ip=01:2 dp=+02 mem[+02]=00");
+    }
+
+    #[test]
+    fn track_uninit_rejects_increments_of_unloaded_cells() {
+        let (_, result, _) = debug_run("+", "--dp-start 100 --track-uninit");
+        assert_eq!(
+            result,
+            Err(ExecError::UninitializedCell { addr: 100, ip: 0 })
+        );
+
+        // the program's own cells, and any --tape-file data loaded after them, count as set
+        let (_, result, _) = debug_run("+", "--track-uninit");
+        assert_eq!(result, Ok(()));
+        let opts = opts("--dp-start 1 --track-uninit --max-steps 10000");
+        let mut mem = load("++");
+        let result = execute_debug(
+            &mut mem,
+            1,
+            2,
+            None,
+            &opts,
+            &mut Stats::default(),
+            &mut Console {
+                input: &mut io::empty(),
+                output: &mut io::sink(),
+            },
+        );
+        assert_eq!(result, Ok(()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interrupt_handler_flags_sigint() {
+        extern "C" {
+            fn raise(signum: i32) -> i32;
+        }
+        install_interrupt_handler();
+        // SAFETY: the handler installed above only stores to an atomic
+        assert_eq!(unsafe { raise(2) }, 0);
+        assert!(INTERRUPTED.swap(false, Ordering::SeqCst));
+    }
+
+    #[test]
+    fn step_delay_paces_from_the_start_of_the_run() {
+        // three instructions, the last scheduled two delays after the first
+        let started = Instant::now();
+        let (_, result, stats) = debug_run("+>", "--dp-start 254 --step-delay 20");
+        assert_eq!((result, stats.steps), (Ok(()), 3));
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(
+            opts("--step-delay 20").step_delay,
+            Some(Duration::from_millis(20))
+        );
+    }
+
+    #[test]
+    fn step_delay_deadlines_do_not_depend_on_earlier_steps() {
+        let start = Instant::now();
+        let delay = Duration::from_millis(20);
+        assert_eq!(due(start, delay, 0), start);
+        assert_eq!(due(start, delay, 7), start + Duration::from_millis(140));
+    }
+
+    // input that takes `delay` to arrive, like a user pausing at the step prompt
+    struct SlowInput {
+        delay: Duration,
+        input: &'static [u8],
+    }
+
+    impl io::Read for SlowInput {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(std::mem::take(&mut self.delay));
+            self.input.read(buf)
+        }
+    }
+
+    #[test]
+    fn step_delay_absorbs_a_pause_at_the_prompt() {
+        // ten instructions due every 20ms, with a 150ms pause before the second; the steps
+        // after the pause catch up on the schedule, so the run ends near 180ms rather than the
+        // 350ms it would take if each delay ran from the step before
+        let opts = opts("--dp-start 100 --step-delay 20 --break 1");
+        let mut mem = load_with("++++++++>[", &opts);
+        let mut input = io::BufReader::new(SlowInput {
+            delay: Duration::from_millis(150),
+            input: b"c\n",
+        });
+        let started = Instant::now();
+        let mut stats = Stats::default();
+        let mut console = Console {
+            input: &mut input,
+            output: &mut io::sink(),
+        };
+        let result = execute_debug(&mut mem, 3, 3, None, &opts, &mut stats, &mut console);
+        let elapsed = started.elapsed();
+        assert_eq!((result, stats.steps), (Ok(()), 10));
+        assert!(elapsed >= Duration::from_millis(180), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(300), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn warn_self_modify_flags_writes_into_the_program() {
+        // other tests warn too, so this can only check that the count went up
+        let before = WARNINGS.load(Ordering::SeqCst);
+        let (mem, result, _) = debug_run("+", "--warn-self-modify");
+        assert_eq!(result, Ok(()));
+        assert_eq!(mem[0], load("+")[0] + 1);
+        assert!(WARNINGS.load(Ordering::SeqCst) > before);
+    }
+
+    #[test]
+    fn dp_start_moves_the_first_increment() {
+        // step past the cell and skip the rest of memory, which would otherwise run cell 42
+        let (result, mem) = run_to_tape("dp-start.ce", "+>[\n", "--dp-start 42");
+        assert!(result.is_ok());
+        let mut expected = load("+>[");
+        expected[42] = 1;
+        assert_eq!(mem, expected);
+
+        let (result, _) = run_to_tape("dp-start-oob.ce", "+\n", "--dp-start 256");
+        assert!(matches!(result, Err(CeError::DpStartOutOfBounds(256))));
+    }
+
+    #[test]
+    fn tui_frame_shows_registers_and_a_grid_of_memory() {
+        let mut mem = load("+>");
+        let mut machine = Machine::new(&mut mem, Encoding::default(), LoopMode::While).with_dp(17);
+        machine.advance().unwrap();
+        let frame = tui_frame(&machine, Instruction::ShiftRight, 1);
+        assert!(frame.contains("step 1  >  ip=00:1  dp=11  rp=00:0  mem[dp]=01"));
+        assert_eq!(
+            frame.lines().filter(|l| l.get(2..4) == Some(": ")).count(),
+            16
+        );
+        assert!(frame.contains("10:  00 \x1b[7m01\x1b[0m 00"));
+        assert!(frame.contains("00:  \x1b[4mb0\x1b[0m 00"));
+    }
+
+    #[test]
+    fn tui_draws_on_the_alternate_screen_until_told_to_stop() {
+        let opts = opts("--tui --dp-start 100");
+        let mut mem = load_with("+>[", &opts);
+        let mut output = Vec::new();
+        let mut console = Console {
+            input: &mut Cursor::new("\nq\n"),
+            output: &mut output,
+        };
+        let result = execute_runs(
+            &mut mem,
+            1,
+            1,
+            None,
+            &opts,
+            &mut Stats::default(),
+            &mut console,
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(mem[100], 1);
+
+        // a frame for each of the first two steps, then `q` runs the rest without the view
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("\x1b[?1049h\x1b[?25l\x1b[H\x1b[2Jstep 1  +"));
+        assert_eq!(output.matches("\x1b[H\x1b[2J").count(), 2);
+        assert!(output.contains("step 2  >"));
+        assert!(output.ends_with("\x1b[?25h\x1b[?1049l"));
+    }
+
+    #[test]
+    fn no_resize_keeps_memory_at_the_program_length() {
+        let (result, mem) = run_to_tape("no-resize.ce", "++>[[+\n", "--no-resize");
+        assert!(result.is_ok());
+        // the increments land on the program's own bytes, `++>[` and `[+`
+        assert_eq!(mem, [0xac + 2, 0x20 + 1]);
+
+        let (result, mem) = run_to_tape("no-resize-empty.ce", "\n", "--no-resize");
+        assert!(result.is_ok());
+        assert!(mem.is_empty());
+    }
+
+    #[test]
+    fn dump_interval_writes_memory_periodically() {
+        let program = temp_file("dump.ce", b"++\n");
+        let dumps: Vec<_> = (0..3).map(|n| format!("{}.dump.{}", program, n)).collect();
+        for dump in &dumps {
+            let _ = fs::remove_file(dump);
+        }
+        // every one of the 1024 instructions runs once, and cell 100 goes from 2 to 3 at step 403
+        let result = run_program(
+            &args(&format!("{} --dp-start 100 --dump-interval 400", program)),
+            false,
+        );
+        assert!(result.is_ok());
+        let mut expected = load("++");
+        expected[100] = 2;
+        assert_eq!(fs::read(&dumps[0]).unwrap(), expected);
+        expected[100] = 3;
+        assert_eq!(fs::read(&dumps[1]).unwrap(), expected);
+        assert!(fs::metadata(&dumps[2]).is_err());
+
+        // the eleventh dump, at step 990, reuses the first name
+        let result = run_program(
+            &args(&format!("{} --dp-start 100 --dump-interval 90", program)),
+            false,
+        );
+        assert!(result.is_ok());
+        assert_eq!(fs::read(&dumps[0]).unwrap(), expected);
+    }
+
+    #[test]
+    fn pgm_is_sixteen_cells_wide() {
+        let mem: Vec<u8> = (0..=255).collect();
+        let image = pgm(&mem);
+        let header = b"P5\n16 16\n255\n";
+        assert_eq!(&image[..header.len()], header);
+        assert_eq!(&image[header.len()..], mem);
+
+        // a short memory is padded to a whole row
+        assert_eq!(
+            pgm(&[7, 8]),
+            b"P5\n16 1\n255\n\x07\x08\0\0\0\0\0\0\0\0\0\0\0\0\0\0"
+        );
+    }
+
+    #[test]
+    fn export_image_writes_the_final_memory() {
+        let program = temp_file("image.ce", b"++\n");
+        let image = format!("{}.pgm", program);
+        let result = run_program(
+            &args(&format!(
+                "{} --dp-start 100 --export-image {}",
+                program, image
+            )),
+            false,
+        );
+        assert!(result.is_ok());
+        let mut mem = load("++");
+        mem[100] = 3;
+        assert_eq!(fs::read(&image).unwrap(), pgm(&mem));
+    }
+
+    #[test]
+    fn tape_file_loads_the_cells_after_the_program() {
+        // `>[>]` walks right to the first zero cell, so it stops past the loaded bytes
+        let data = temp_file("tape.bin", &[2, 2]);
+        let json = temp_file("tape.json", b"");
+        let (result, tape) = run_to_tape(
+            "tape.ce",
+            ">[>]\n",
+            &format!("--tape-file {} --stats-json {}", data, json),
+        );
+        assert!(result.is_ok());
+        assert_eq!(tape[..4], [0xcd, 2, 2, 0]);
+        assert!(fs::read_to_string(&json).unwrap().contains("\"dp\":3,"));
+    }
+
+    #[test]
+    fn tape_file_is_truncated_to_fit() {
+        let before = WARNINGS.load(Ordering::SeqCst);
+        // each data cell runs as `++++` on the program's own cell
+        let data = temp_file("tape-long.bin", &[0xaa; 300]);
+        let (result, tape) = run_to_tape("tape-long.ce", "+\n", &format!("--tape-file {}", data));
+        assert!(result.is_ok());
+        assert_eq!(tape.len(), 256);
+        assert_eq!(tape[0], ((0x81 + 4 * 255) % 256) as u8);
+        assert!(tape[1..].iter().all(|&b| b == 0xaa));
+        assert!(WARNINGS.load(Ordering::SeqCst) > before);
+    }
+
+    #[test]
+    fn annotation_numbers_each_instruction_by_byte_and_slot() {
+        let program = Instruction::from_chars(b"+>[+]".iter().copied())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            annotation(&program),
+            "[0] 00:0 +\n[1] 00:1 >\n[2] 00:2 [\n[3] 00:3 +\n[4] 01:0 ]\n"
+        );
+    }
+
+    #[test]
+    fn annotate_reads_the_program_file() {
+        let path = temp_file("annotate.ce", b"+>\n");
+        assert!(annotate(&args(&path)).is_ok());
+        let missing = format!("{}.missing", path);
+        assert!(matches!(annotate(&args(&missing)), Err(CeError::Io { .. })));
+    }
+
+    #[test]
+    fn a_directory_is_not_a_program() {
+        let dir = env::temp_dir().to_string_lossy().into_owned();
+        let error = run_program(&args(&dir), false).unwrap_err();
+        assert!(matches!(&error, CeError::IsDirectory(path) if *path == dir));
+        assert_eq!(
+            error.to_string(),
+            format!("'{}' is a directory, expected a program file", dir)
+        );
+        assert_eq!(error.exit_code(), 4);
+    }
+
+    #[test]
+    fn cells_touched_counts_each_written_cell_once() {
+        let (mem, result, stats) = debug_run("++>++>++>[", "--dp-start 100");
+        assert_eq!(result, Ok(()));
+        assert_eq!(mem[100..103], [2, 2, 2]);
+        assert_eq!(stats.touched.len(), 3);
+    }
+
+    #[test]
+    fn ring_runs_until_the_step_limit() {
+        let (mem, result, stats) = debug_run("+>+", "--ring --dp-start 100 --max-steps 30");
+        assert!(matches!(
+            result,
+            Err(ExecError::StepLimitExceeded { steps: 30, .. })
+        ));
+        assert_eq!(stats.steps, 30);
+        assert_eq!(mem[100..111], [1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1]);
+
+        let (result, _) = run_to_tape("ring-empty.ce", "\n", "--ring");
+        assert!(matches!(result, Err(CeError::EmptyRing)));
+    }
+
+    #[test]
+    fn bad_command_lines_are_usage_errors() {
+        let error = |line: &str| match Options::parse(&args(line), 1, RUN_OPTIONS) {
+            Err(e) => e,
+            Ok(_) => panic!("'{}' parsed", line),
+        };
+        assert!(matches!(error(""), CeError::Usage));
+        assert!(matches!(
+            error("p.ce --max-steps"),
+            CeError::MissingValue {
+                expected: "a step count",
+                ..
+            }
+        ));
+        assert!(matches!(
+            error("p.ce --max-steps many"),
+            CeError::InvalidValue { value, .. } if value == "many"
+        ));
+        assert!(matches!(
+            error("p.ce --repeat 0"),
+            CeError::InvalidValue { .. }
+        ));
+        assert!(matches!(error("p.ce --fast"), CeError::UnknownOption(o) if o == "--fast"));
+        assert!(matches!(error("p.ce q.ce"), CeError::UnexpectedArgument(a) if a == "q.ce"));
+        assert!(matches!(
+            Options::parse(&args("p.ce"), 2, RUN_OPTIONS),
+            Err(CeError::Usage)
+        ));
+        for line in ["", "p.ce --max-steps", "p.ce --fast", "p.ce q.ce"] {
+            assert_eq!(error(line).exit_code(), 2);
+        }
+    }
+
+    #[test]
+    fn bad_programs_are_errors() {
+        let (result, _) = run_to_tape("invalid.ce", "+x\n", "");
+        let error = result.unwrap_err();
+        assert!(matches!(error, CeError::InvalidInstruction(b'x')));
+        assert_eq!(error.exit_code(), 3);
+
+        let (result, _) = run_to_tape("too-large.ce", &format!("{}\n", "+".repeat(1025)), "");
+        let error = result.unwrap_err();
+        assert!(matches!(error, CeError::ProgramTooLarge(257)));
+        assert_eq!(error.exit_code(), 3);
+
+        let (result, _) = run_to_tape("oob.ce", "+>\n", "--dp-start 255");
+        let error = result.unwrap_err();
+        assert!(matches!(
+            error,
+            CeError::Exec(ExecError::DataPointerOutOfBounds { ip: 1 })
+        ));
+        assert_eq!(error.exit_code(), 1);
+    }
+
+    #[test]
+    fn opcode_report_lists_each_instruction_that_ran() {
+        let (_, result, stats) = debug_run("+[+]", "--dp-start 100 --time-opcodes");
+        assert_eq!(result, Ok(()));
+        let report = stats.opcode_report();
+        let rows: Vec<(&str, &str)> = report
+            .lines()
+            .map(|l| {
+                let fields: Vec<_> = l.split_whitespace().collect();
+                (fields[0], fields[3])
+            })
+            .collect();
+        // the second `[` sees the cleared cell and skips the rest of memory; `>` never ran, so
+        // it has no row
+        assert_eq!(rows, [("[", "2"), ("]", "255"), ("+", "256")]);
+        assert!(stats.opcode_time[Instruction::Increment as usize] > Duration::ZERO);
+    }
+
+    #[test]
+    fn help_lists_every_option_parse_accepts() {
+        // `--loop=while|do-while` documents both `--loop=while` and `--loop=do-while`
+        let documented: Vec<String> = OPTIONS
+            .iter()
+            .flat_map(|(usage, _)| {
+                let option = usage.split(' ').next().unwrap();
+                match option.split_once('=') {
+                    Some((name, values)) => values
+                        .split('|')
+                        .map(|v| format!("{}={}", name, v))
+                        .collect(),
+                    None => vec![option.to_string()],
+                }
+            })
+            .collect();
+
+        // the option strings in the arms of Options::parse's match
+        let source = include_str!("main.rs");
+        let body = &source[source.find("fn parse(args: &[String]").unwrap()..];
+        let body = &body[..body.find("_ if option.starts_with").unwrap()];
+        let parsed: Vec<&str> = body
+            .lines()
+            .map(str::trim_start)
+            .filter(|line| line.starts_with("\"--") && line.contains("=>"))
+            .flat_map(|line| line[..line.find("=>").unwrap()].split('|'))
+            .map(|option| option.trim().trim_matches('"'))
+            .collect();
+        assert!(parsed.len() > 40, "found only {:?}", parsed);
+
+        for option in &parsed {
+            assert!(
+                documented.iter().any(|d| d == option),
+                "{} isn't in help",
+                option
+            );
+        }
+        for option in &documented {
+            let handled = parsed.contains(&option.as_str()) || option == "--help";
+            assert!(handled, "{} is in help but never parsed", option);
+        }
+        assert!(help().contains("  --loop=while|do-while  "));
+        assert!(run(&args("help")).is_ok());
+        assert!(run(&args("--help")).is_ok());
+    }
+
+    #[test]
+    fn commands_dispatch_and_run_is_the_default() {
+        let path = temp_file("dispatch.ce", b"+\n");
+        assert!(run(&args(&format!("assemble {}", path))).is_ok());
+        assert!(run(&args(&format!("run {}", path))).is_ok());
+        assert!(run(&args(&path)).is_ok());
+        // anything that isn't a command is the program file
+        assert!(matches!(run(&args("disasm")), Err(CeError::Io { path, .. }) if path == "disasm"));
+        assert!(matches!(run(&args("annotate")), Err(CeError::Usage)));
+    }
+
+    #[test]
+    fn commands_reject_options_they_ignore() {
+        let path = temp_file("options.ce", b"+\n");
+        let rejects = |line: &str, option: &str| {
+            let result = run(&args(&line.replace("p.ce", &path)));
+            assert!(
+                matches!(&result, Err(CeError::UnknownOption(o)) if o == option),
+                "{}",
+                line
+            );
+        };
+        rejects("assemble p.ce --golden x", "--golden");
+        rejects("equiv p.ce p.ce --ring --no-resize", "--ring");
+        rejects(
+            "equiv p.ce p.ce --tape-dump-on-error",
+            "--tape-dump-on-error",
+        );
+        rejects("annotate p.ce --emit=c", "--emit=c");
+        rejects("info p.ce --encoding --trace", "--trace");
+        rejects("p.ce --random 3", "--random");
+        assert!(run(&args(&format!(
+            "assemble {} --emit=c --bit-order=lsb",
+            path
+        )))
+        .is_ok());
+        assert!(run(&args(&format!("info {} --encoding --macros", path))).is_ok());
+
+        // every accepted option is documented, and every documented option has a command
+        let lists = [
+            RUN_OPTIONS,
+            EQUIV_OPTIONS,
+            ASSEMBLE_OPTIONS,
+            ANNOTATE_OPTIONS,
+            INFO_OPTIONS,
+        ];
+        let name = |usage: &str| usage.split([' ', '=']).next().unwrap().to_string();
+        let documented: Vec<String> = OPTIONS.iter().map(|(usage, _)| name(usage)).collect();
+        for option in lists.concat() {
+            assert!(documented.iter().any(|d| d == option), "{}", option);
+        }
+        for option in documented.iter().filter(|&d| d != "--help") {
+            assert!(lists.concat().contains(&option.as_str()), "{}", option);
+        }
+    }
+
+    // `ce transform` on a program that increments the first two cells of its input
+    fn transform(name: &str, expected: &[u8]) -> Result<(), CeError> {
+        let program = temp_file(&format!("{}.ce", name), b">>+>+>[\n");
+        let input = temp_file(&format!("{}.in", name), &[1, 1]);
+        let expect = temp_file(&format!("{}.expect", name), expected);
+        run_program(
+            &args(&format!("{} --in {} --expect {}", program, input, expect)),
+            true,
+        )
+    }
+
+    #[test]
+    fn transform_checks_the_output_cells() {
+        assert!(transform("transform-match", &[2, 2, 0]).is_ok());
+        assert!(matches!(
+            transform("transform-mismatch", &[2, 3]),
+            Err(CeError::TransformMismatch {
+                addr: 3,
+                expected: 3,
+                actual: Some(2)
+            })
+        ));
+
+        // the 254 cells after the program are all the output there is
+        let mut long = vec![2, 2];
+        long.resize(254, 0);
+        assert!(transform("transform-full", &long).is_ok());
+        long.push(0);
+        assert!(matches!(
+            transform("transform-long", &long),
+            Err(CeError::TransformMismatch {
+                addr: 256,
+                expected: 0,
+                actual: None
+            })
+        ));
+    }
+
+    #[test]
+    fn transform_needs_input_and_expected_output() {
+        let program = temp_file("transform-bare.ce", b"+\n");
+        for line in ["", "--in x", "--expect x"] {
+            let result = run_program(&args(&format!("{} {}", program, line)), true);
+            assert!(matches!(result, Err(CeError::OptionRequires { .. })));
+        }
+    }
+
+    #[test]
+    fn bracket_table_shows_where_each_open_skips_to() {
+        let program = |src: &[u8]| {
+            Instruction::from_chars(src.iter().copied())
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        assert_eq!(
+            bracket_table(&program(b"[+[>]+[]]")),
+            "[ at 0 skips to 9\n[ at 2 skips to 5\n[ at 6 skips to 8\n\
+             ] returns to the last [ entered\n"
+        );
+        assert_eq!(
+            bracket_table(&program(b"]+[[]")),
+            "[ at 2 skips past the end of the program\n[ at 3 skips to 5\n\
+             ] returns to the last [ entered\n"
+        );
+        assert_eq!(bracket_table(&program(b"+>")), "");
+
+        // each target is where the interpreter lands when the `[` skips over a zero cell
+        let source = b"[+[>]+[]]";
+        let mut mem = pack(program(source).into_iter(), &Encoding::default());
+        mem.resize(256, 0);
+        for (start, end) in [(0, 9), (2, 5), (6, 8)] {
+            assert_eq!(loop_exit(&mem, start, &Encoding::default()), end);
+        }
+    }
+
+    // `ce equiv` on two sources
+    fn equiv_of(name: &str, a: &str, b: &str, line: &str) -> Result<(), CeError> {
+        let a = temp_file(&format!("{}-a.ce", name), a.as_bytes());
+        let b = temp_file(&format!("{}-b.ce", name), b.as_bytes());
+        equiv(&args(&format!("{} {} {}", a, b, line)))
+    }
+
+    #[test]
+    fn equiv_compares_the_data_both_programs_leave() {
+        // both only increment their own first byte
+        assert!(equiv_of("equiv-same", "++\n", "+[+\n", "").is_ok());
+        assert!(equiv_of("equiv-random", "++\n", "+[+\n", "--random 3").is_ok());
+
+        let over = temp_file("equiv.in", &[5]);
+        let result = equiv_of(
+            "equiv-over",
+            ">>++>[\n",
+            ">>+>[\n",
+            &format!("--over {}", over),
+        );
+        assert!(matches!(
+            result,
+            Err(CeError::NotEquivalent {
+                differing: 1,
+                inputs: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn equiv_compares_how_the_programs_finish() {
+        // the first runs off the end of memory, the second halts
+        let result = equiv_of("equiv-status", ">\n", "+\n", "--dp-start 255");
+        assert!(matches!(
+            result,
+            Err(CeError::NotEquivalent {
+                differing: 1,
+                inputs: 1
+            })
+        ));
+        let result = equiv_of("equiv-oob", "+\n", "+\n", "--dp-start 256");
+        assert!(matches!(result, Err(CeError::DpStartOutOfBounds(256))));
+    }
+
+    #[test]
+    fn max_dp_stops_a_move_past_the_limit() {
+        let (mem, result, _) = debug_run("+>+>+", "--dp-start 100 --max-dp 101");
+        assert_eq!(result, Err(ExecError::MaxDpExceeded { dp: 102, ip: 3 }));
+        assert_eq!(mem[100..103], [1, 1, 0]);
+        assert_eq!(status(result.as_ref().err()), "max_dp");
+
+        let (_, result, stats) = debug_run("+>+>[", "--dp-start 100 --max-dp 102");
+        assert_eq!(result, Ok(()));
+        assert_eq!(stats.max_dp, 102);
+    }
+
+    #[test]
+    fn step_prompt_reads_commands_until_one_resumes() {
+        let mut mem = load("+>");
+        let machine = Machine::new(&mut mem, Encoding::default(), LoopMode::While);
+        // registers, memory and an unknown command each prompt again
+        let mut input = Cursor::new("r\nd\nx\nq\nc\n");
+        assert_eq!(step_prompt(&machine, &mut input), StepCommand::Quit);
+        assert_eq!(step_prompt(&machine, &mut input), StepCommand::Continue);
+    }
+
+    #[test]
+    fn step_runs_on_commands_from_the_console() {
+        let (plain, result, _) = debug_run("+>+>", "--dp-start 100");
+        assert_eq!(result, Ok(()));
+        assert_eq!(plain[100..102], [1, 1]);
+
+        // continuing from the first prompt finishes the run as if --step weren't given
+        let (mem, result, _) = prompted_run("+>+>", "--dp-start 100 --step", "c\n");
+        assert_eq!(result, Ok(()));
+        assert_eq!(mem, plain);
+
+        // stepping once executes only the first `+` before quitting at the `>` after it
+        let (mem, result, stats) = prompted_run("+>+>", "--dp-start 100 --step", "s\nq\n");
+        assert_eq!(result, Err(ExecError::Quit { ip: 1 }));
+        assert_eq!(mem[100..102], [1, 0]);
+        assert_eq!(stats.touched, HashSet::from([100]));
+    }
+
+    #[test]
+    fn fail_on_warning_turns_warnings_into_a_failure() {
+        let (result, _) = run_to_tape("warn.ce", "+\n", "--warn-self-modify");
+        assert!(result.is_ok());
+
+        let (result, _) = run_to_tape(
+            "warn-fail.ce",
+            "+\n",
+            "--warn-self-modify --fail-on-warning",
+        );
+        let error = result.unwrap_err();
+        assert!(matches!(error, CeError::WarningsEmitted(n) if n > 0));
+        assert_eq!(error.exit_code(), 1);
+    }
+
+    #[test]
+    fn halt_on_stable_stops_a_ring_at_a_fixed_point() {
+        // cell 10 is zero, so each pass skips the loop and changes nothing
+        let (_, result, stats) = debug_run("[+]", "--ring --dp-start 10 --halt-on-stable");
+        assert_eq!(result, Ok(()));
+        assert_eq!(stats.steps, 1);
+
+        // every pass increments, so only the step limit stops it
+        let (_, result, _) = debug_run(
+            "+",
+            "--ring --dp-start 100 --halt-on-stable --max-steps 1000",
+        );
+        assert!(matches!(
+            result,
+            Err(ExecError::StepLimitExceeded { steps: 1000, .. })
+        ));
+    }
+
+    #[test]
+    fn halt_on_stable_stops_repeating_once_a_run_changes_nothing() {
+        // the first run clears cell 10 and the second leaves it cleared; the other 48 are skipped
+        let json = temp_file("stable.json", b"");
+        let (result, _) = run_to_tape(
+            "stable.ce",
+            "[+]\n",
+            &format!(
+                "--tape-fill 1 --dp-start 10 --repeat 50 --halt-on-stable --stats-json {}",
+                json
+            ),
+        );
+        assert!(result.is_ok());
+        assert!(fs::read_to_string(&json)
+            .unwrap()
+            .starts_with("{\"steps\":514,"));
+
+        let result = Options::parse(&args("p.ce --halt-on-stable"), 1, RUN_OPTIONS);
+        assert!(matches!(result, Err(CeError::OptionRequires { .. })));
+        assert!(Options::parse(&args("p.ce --halt-on-stable --repeat 2"), 1, RUN_OPTIONS).is_ok());
+    }
+
+    #[test]
+    fn byte_format_runs_like_the_source_it_was_assembled_from() {
+        let src = "++>+[>]>\n";
+        let hex = Emit::Hex.format(&pack(
+            Instruction::from_chars(src.trim().bytes()).map(Result::unwrap),
+            &Encoding::default(),
+        ));
+        assert_eq!(hex, "ae 37\n");
+        let (result, from_source) = run_to_tape("bytes-src.ce", src, "--dp-start 100");
+        assert!(result.is_ok());
+        let (result, from_bytes) = run_to_tape("bytes.ce", &hex, "--dp-start 100 --format=bytes");
+        assert!(result.is_ok());
+        assert_eq!(from_bytes, from_source);
+    }
+
+    #[test]
+    fn step_limit_reports_the_loop_that_never_ended() {
+        // `]` keeps jumping back to itself, once per step after the first three
+        let (_, result, _) = debug_run(">+[]", "--dp-start 100 --max-steps 100000");
+        assert_eq!(
+            result,
+            Err(ExecError::StepLimitExceeded {
+                steps: 100000,
+                ip: 3,
+                loop_start: Some(2),
+                iterations: 99997,
+            })
+        );
+    }
+
+    #[test]
+    fn encoding_report_counts_bits_and_padding() {
+        let program: Vec<_> = Instruction::from_chars(b"+>[+]".iter().copied())
+            .map(Result::unwrap)
+            .collect();
+        let enc = Encoding {
+            bit_order: BitOrder::Lsb,
+            ..Encoding::parse("00=+,01=>,10=[,11=]").unwrap()
+        };
+        assert_eq!(
+            encoding_report(&program, &enc).unwrap(),
+            "instructions     5\n\
+             bits each        2\n\
+             bytes            2 of 256\n\
+             padding pairs    3, decoding as +\n\
+             density          62.5%\n\
+             opcode map       00=+,01=>,10=[,11=]\n\
+             bit order        lsb\n"
+        );
+        assert_eq!(Encoding::default().bits(), 2);
+    }
+
+    #[test]
+    fn info_needs_a_report_to_make() {
+        let path = temp_file("info.ce", b"+>\n");
+        assert!(info(&args(&format!("{} --encoding", path))).is_ok());
+        assert!(matches!(
+            info(&args(&path)),
+            Err(CeError::OptionRequires { .. })
+        ));
+
+        // a program that can't be loaded has no packing to report
+        let path = temp_file(
+            "info-large.ce",
+            format!("{}\n", "+".repeat(2000)).as_bytes(),
+        );
+        let result = info(&args(&format!("{} --encoding", path)));
+        assert!(matches!(result, Err(CeError::ProgramTooLarge(500))));
+        let full = vec![Instruction::Increment; 1024];
+        assert!(encoding_report(&full, &Encoding::default()).is_ok());
+    }
+
+    #[test]
+    fn run_until_stops_when_the_cell_first_reaches_the_value() {
+        let (mem, result, stats) = debug_run(">>+++++>[", "--run-until cell=2,value=3");
+        assert_eq!(result, Ok(()));
+        // the third increment is the fifth instruction
+        assert_eq!(stats.watch_hit, Some(5));
+        assert_eq!(stats.steps, 5);
+        assert_eq!(mem[2], 3);
+
+        let (_, result, stats) = debug_run(">>+++++>[", "--run-until cell=2,value=9");
+        assert_eq!(result, Ok(()));
+        assert_eq!(stats.watch_hit, None);
+    }
+
+    #[test]
+    fn run_until_takes_a_cell_and_a_value() {
+        assert_eq!(
+            opts("--run-until cell=2,value=0x10").run_until,
+            Some((2, 0x10))
+        );
+        for watch in [
+            "cell=2",
+            "value=3,cell=2",
+            "cell=x,value=3",
+            "cell=2,value=256",
+        ] {
+            let result = Options::parse(
+                &args(&format!("p.ce --run-until {}", watch)),
+                1,
+                RUN_OPTIONS,
+            );
+            assert!(
+                matches!(result, Err(CeError::InvalidValue { .. })),
+                "{}",
+                watch
+            );
+        }
+    }
+
+    #[test]
+    fn trace_golden_checks_a_recorded_trace() {
+        let program = temp_file("trace-golden.ce", b"+>[\n");
+        let golden = format!("{}.trace", program);
+        let _ = fs::remove_file(&golden);
+        let with = |option: &str| {
+            let line = format!("{} --dp-start 100 {} {}", program, option, golden);
+            run_program(&args(&line), false)
+        };
+        let run = || with("--trace-golden");
+
+        // a golden that doesn't exist yet fails instead of being recorded
+        assert!(matches!(run(), Err(CeError::Io { path, .. }) if path == golden));
+        assert!(fs::metadata(&golden).is_err());
+
+        assert!(with("--record-trace").is_ok());
+        let recorded = fs::read_to_string(&golden).unwrap();
+        let lines: Vec<&str> = recorded.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(run().is_ok());
+
+        let altered = lines[1].replace("dp=64", "dp=65");
+        fs::write(
+            &golden,
+            format!("{}\n{}\n{}\n", lines[0], altered, lines[2]),
+        )
+        .unwrap();
+        match run() {
+            Err(CeError::TraceMismatch {
+                step,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(step, 2);
+                assert_eq!(expected.as_deref(), Some(altered.as_str()));
+                assert_eq!(actual.as_deref(), Some(lines[1]));
+            }
+            other => panic!("expected a mismatch at step 2, got {:?}", other),
+        }
+
+        fs::write(&golden, format!("{}\n{}\n", lines[0], lines[1])).unwrap();
+        assert!(matches!(
+            run(),
+            Err(CeError::TraceMismatch {
+                step: 3,
+                expected: None,
+                actual: Some(_)
+            })
+        ));
+    }
+
+    #[test]
+    fn trace_golden_can_include_cells() {
+        let program = temp_file("trace-golden-cells.ce", b"+>[\n");
+        let golden = format!("{}.trace", program);
+        let _ = fs::remove_file(&golden);
+        let line = |option: &str| {
+            format!(
+                "{} --dp-start 100 --trace-cells 100:102 {} {}",
+                program, option, golden
+            )
+        };
+        assert!(run_program(&args(&line("--record-trace")), false).is_ok());
+        assert!(fs::read_to_string(&golden)
+            .unwrap()
+            .lines()
+            .all(|l| l.contains("cells[64..66]=")));
+        assert!(run_program(&args(&line("--trace-golden")), false).is_ok());
+    }
+
+    // programs with the final dumps the original nested-loop interpreter printed for them;
+    // its `[` never skipped, which is what --loop=do-while does now
+    const BASELINE: [(&str, &str); 6] = [
+        ("prog.ce", "testdata/baseline/prog.dump"),
+        (
+            "testdata/baseline/random-1.ce",
+            "testdata/baseline/random-1.dump",
+        ),
+        (
+            "testdata/baseline/random-2.ce",
+            "testdata/baseline/random-2.dump",
+        ),
+        (
+            "testdata/baseline/random-3.ce",
+            "testdata/baseline/random-3.dump",
+        ),
+        (
+            "testdata/baseline/random-4.ce",
+            "testdata/baseline/random-4.dump",
+        ),
+        (
+            "testdata/baseline/random-5.ce",
+            "testdata/baseline/random-5.dump",
+        ),
+    ];
+
+    #[test]
+    fn both_interpreters_reproduce_the_original_dumps() {
+        let root = env!("CARGO_MANIFEST_DIR");
+        for (program, dump) in BASELINE {
+            // most of these end differently under the default `while` loop; --max-steps moves
+            // the run from execute_fast to execute_debug
+            for extra in ["", "--max-steps 1000000"] {
+                let line = format!(
+                    "{}/{} --loop=do-while --golden {}/{} {}",
+                    root, program, root, dump, extra
+                );
+                let result = run_program(&args(&line), false);
+                assert!(result.is_ok(), "{} {}: {:?}", program, extra, result);
+            }
+        }
+    }
+
+    #[test]
+    fn assemble_rejects_a_program_too_large_for_memory() {
+        let path = temp_file(
+            "assemble-large.ce",
+            format!("{}\n", "+".repeat(2000)).as_bytes(),
+        );
+        let result = assemble(&args(&path));
+        assert!(matches!(result, Err(CeError::ProgramTooLarge(500))));
 
-    fn to_byte(is: Vec<Instruction>) -> u8 {
-        Instruction::to_pair(is.get(0)) << 6
-            | Instruction::to_pair(is.get(1)) << 4
-            | Instruction::to_pair(is.get(2)) << 2
-            | Instruction::to_pair(is.get(3))
+        let path = temp_file(
+            "assemble-full.ce",
+            format!("{}\n", "+".repeat(1024)).as_bytes(),
+        );
+        assert!(assemble(&args(&path)).is_ok());
     }
 }